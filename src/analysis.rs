@@ -0,0 +1,186 @@
+//! Static analysis of the head position over the instruction tree.
+
+use crate::Instruction;
+
+/// Net head movement of a sequence of instructions, or `None` if it depends on the tape contents,
+/// because of scans or loops that don't return to their starting cell
+pub fn net_movement(instructions: &[Instruction]) -> Option<isize> {
+    let mut movement = 0;
+
+    for instr in instructions {
+        match instr {
+            Instruction::IncrementPointer(_) => movement += 1,
+            Instruction::DecrementPointer(_) => movement -= 1,
+            Instruction::Move { amount, .. } => movement += amount,
+            Instruction::ScanRight(_) | Instruction::ScanLeft(_) => return None,
+            Instruction::Loop(nested_instructions) if !is_balanced(nested_instructions) => return None,
+            _ => ()
+        }
+    }
+
+    Some(movement)
+}
+
+/// Whether a loop body always ends on the cell it started on, so that every iteration starts at the same cell
+pub fn is_balanced(body: &[Instruction]) -> bool {
+    net_movement(body) == Some(0)
+}
+
+/// What the analysis could prove about a program
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    /// Lowest cell the program can reach, relative to the cell the head starts on
+    pub min_offset: isize,
+    /// Highest cell the program can reach, relative to the cell the head starts on
+    pub max_offset: isize,
+    /// Whether the head position is known throughout the program, so that the offsets bound every cell it reaches.
+    ///
+    /// Otherwise they only cover the code before the head position gets lost.
+    pub complete: bool,
+    /// Loops whose body ends on the cell it started on
+    pub balanced_loops: usize,
+    /// Loops whose body may move the head, which loses track of its position
+    pub unbalanced_loops: usize
+}
+
+impl Analysis {
+    /// Number of cells from the start of the tape that the program can reach, if it is statically known.
+    ///
+    /// This is an upper bound: code that never runs, like a loop on a zero cell, counts as well.
+    pub fn tape_extent(&self) -> Option<usize> {
+        match self.complete && self.min_offset >= 0 {
+            true => Some(self.max_offset as usize + 1),
+            false => None
+        }
+    }
+
+    fn reach(&mut self, cell: isize) {
+        self.min_offset = self.min_offset.min(cell);
+        self.max_offset = self.max_offset.max(cell);
+    }
+
+    /// Follows the instructions from a head `position`, if known, returning the position after them
+    fn walk(&mut self, instructions: &[Instruction], mut position: Option<isize>) -> Option<isize> {
+        for instr in instructions {
+            let position_before = position;
+            let cell = match instr {
+                Instruction::IncrementPointer(_) => Some(1),
+                Instruction::DecrementPointer(_) => Some(-1),
+                Instruction::Move { amount, .. } => Some(*amount),
+                Instruction::AddAt { offset, .. } | Instruction::SetZeroAt { offset, .. } | Instruction::ReadAt { offset, .. } | Instruction::WriteAt { offset, .. } => Some(*offset),
+                Instruction::MulAdd { offset, .. } => Some(*offset),
+                Instruction::ScanRight(_) | Instruction::ScanLeft(_) => {
+                    position = None;
+                    None
+                },
+                Instruction::Loop(nested_instructions) => {
+                    // The first iteration starts at a known position either way
+                    self.walk(nested_instructions, position);
+                    match is_balanced(nested_instructions) {
+                        true => self.balanced_loops += 1,
+                        false => {
+                            self.unbalanced_loops += 1;
+                            position = None;
+                        }
+                    }
+                    None
+                },
+                _ => None
+            };
+
+            if let (Some(position), Some(cell)) = (position_before, cell) {
+                self.reach(position + cell);
+            }
+            if let Instruction::IncrementPointer(_) | Instruction::DecrementPointer(_) | Instruction::Move { .. } = instr {
+                position = position.zip(cell).map(|(position, amount)| position + amount);
+            }
+            if position.is_none() {
+                self.complete = false;
+            }
+        }
+
+        position
+    }
+}
+
+/// Analyzes a whole program, whose head starts on cell 0
+pub fn analyze(program: &[Instruction]) -> Analysis {
+    let mut analysis = Analysis { min_offset: 0, max_offset: 0, complete: true, balanced_loops: 0, unbalanced_loops: 0 };
+    analysis.walk(program, Some(0));
+    analysis
+}
+
+/// Follows the head position during code generation, as far as it is statically known,
+/// so that backends can leave out bounds checks that can't fail
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HeadTracker {
+    position: Option<isize>,
+    tape_size: u64
+}
+
+impl HeadTracker {
+    /// Starts with the head on the first cell of a tape of `tape_size` cells
+    pub fn new(tape_size: u64) -> Self {
+        HeadTracker { position: Some(0), tape_size }
+    }
+
+    /// Whether the cell at `offset` from the head is provably on the tape
+    pub fn in_bounds(&self, offset: isize) -> bool {
+        match self.position {
+            Some(position) => position + offset >= 0 && ((position + offset) as u64) < self.tape_size,
+            None => false
+        }
+    }
+
+    pub fn moved(&mut self, amount: isize) {
+        self.position = self.position.map(|position| position + amount);
+    }
+
+    /// Forgets the position, after a scan or before and after a loop that may move the head
+    pub fn forget(&mut self) {
+        self.position = None;
+    }
+
+    /// Prepares for a loop body, whose iterations can only be followed if they all start on the same cell
+    pub fn enter_loop(&mut self, body: &[Instruction]) {
+        if !is_balanced(body) {
+            self.forget();
+        }
+    }
+
+    /// Restores the position after a loop that was entered with `enter_loop`
+    pub fn leave_loop(&mut self, body: &[Instruction]) {
+        if !is_balanced(body) {
+            self.forget();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex, parse};
+
+    fn analyze_source(source: &str) -> Analysis {
+        analyze(&parse(lex(source)).unwrap())
+    }
+
+    #[test]
+    fn straight_line_code_has_a_known_extent() {
+        let analysis = analyze_source(">>+<.>>>-");
+        assert_eq!(analysis.tape_extent(), Some(5));
+    }
+
+    #[test]
+    fn moving_left_of_the_start_has_no_extent() {
+        let analysis = analyze_source("+<-");
+        assert_eq!((analysis.min_offset, analysis.complete, analysis.tape_extent()), (-1, true, None));
+    }
+
+    #[test]
+    fn loops_are_classified_even_after_the_position_is_lost() {
+        let analysis = analyze_source("+[>+<-][>]+[>[-]<-]");
+        assert_eq!((analysis.balanced_loops, analysis.unbalanced_loops), (3, 1));
+        assert!(!analysis.complete);
+    }
+}
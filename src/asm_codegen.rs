@@ -0,0 +1,470 @@
+use std::{path::Path, process::Command};
+
+use crate::{backend::{string_literal, Backend, BoundsChecks}, temp_path, CompileOptions, EofBehavior, Error, Instruction, OutputKind, TapeStorage};
+
+/// Generates GNU-syntax x86-64 assembly for Linux, calling into libc for I/O.
+///
+/// The head lives in `%rbx`, the start of the tape in `%r12` and its size in bytes in `%r13`, all callee-saved
+/// so that they survive libc calls.
+struct AsmGenerator {
+    code: String,
+    options: CompileOptions,
+    checks: BoundsChecks,
+    /// Counter making local labels unique
+    labels: u64
+}
+
+impl AsmGenerator {
+    fn new(options: &CompileOptions) -> Self {
+        let mut generator = AsmGenerator {
+            code: String::new(),
+            options: options.clone(),
+            checks: BoundsChecks::new(options),
+            labels: 0
+        };
+        generator.prelude();
+        generator
+    }
+
+    fn cell_bytes(&self) -> u64 {
+        self.options.cell_bits as u64 / 8
+    }
+
+    /// The AT&T size suffix of a cell
+    fn suffix(&self) -> &'static str {
+        match self.options.cell_bits {
+            8 => "b",
+            16 => "w",
+            32 => "l",
+            _ => "q"
+        }
+    }
+
+    /// The part of `%rax` as wide as a cell
+    fn rax(&self) -> &'static str {
+        match self.options.cell_bits {
+            8 => "%al",
+            16 => "%ax",
+            32 => "%eax",
+            _ => "%rax"
+        }
+    }
+
+    /// Operand for the cell at `offset` from the head
+    fn cell(&self, offset: isize) -> String {
+        match offset {
+            0 => "(%rbx)".to_string(),
+            _ => format!("{}(%rbx)", offset * self.cell_bytes() as isize)
+        }
+    }
+
+    fn instr(&mut self, instr: &str) {
+        self.code.push('\t');
+        self.code.push_str(instr);
+        self.code.push('\n');
+    }
+
+    fn label(&mut self, label: &str) {
+        self.code.push_str(label);
+        self.code.push_str(":\n");
+    }
+
+    /// Returns a fresh `.L<name><n>` label
+    fn new_label(&mut self, name: &str) -> String {
+        self.labels += 1;
+        format!(".L{}{}", name, self.labels)
+    }
+
+    /// Writes the helpers and the start of `main`, up to the tape setup `options` call for
+    fn prelude(&mut self) {
+        let tape_bytes = self.options.tape_size * self.cell_bytes();
+
+        self.instr(".text");
+        if self.options.checked {
+            self.instr(".section .rodata");
+            self.label(".Lout_of_bounds_message");
+            self.instr(".asciz \"rustfuck: tape head out of bounds at %llu:%llu\\n\"");
+            self.instr(".text");
+
+            // rustfuck_out_of_bounds(line, column): keeps the output so far, reports the position and exits.
+            // It never returns, so the tape registers are free to hold the position.
+            self.label("rustfuck_out_of_bounds");
+            self.instr("push %rbx");
+            self.instr("mov %rdi, %rbx");
+            self.instr("mov %rsi, %r12");
+            self.instr("xor %edi, %edi");
+            self.instr("call fflush@PLT");
+            self.instr("mov $2, %edi");
+            self.instr("lea .Lout_of_bounds_message(%rip), %rsi");
+            self.instr("mov %rbx, %rdx");
+            self.instr("mov %r12, %rcx");
+            self.instr("xor %eax, %eax");
+            self.instr("call dprintf@PLT");
+            self.instr("mov $1, %edi");
+            self.instr("call exit@PLT");
+        }
+
+        // rustfuck_getchar(): flushes so that prompts are visible, then reads a byte
+        self.label("rustfuck_getchar");
+        self.instr("sub $8, %rsp");
+        self.instr("xor %edi, %edi");
+        self.instr("call fflush@PLT");
+        self.instr("call getchar@PLT");
+        self.instr("add $8, %rsp");
+        self.instr("ret");
+
+        if let TapeStorage::Global = self.options.tape_storage {
+            self.instr(".local tape");
+            self.instr(&format!(".comm tape, {}, 64", tape_bytes));
+        }
+
+        self.instr(".globl main");
+        self.instr(".type main, @function");
+        self.label("main");
+        // The return address, four saved registers and padding keep the stack 16-byte aligned for calls
+        self.instr("push %rbp");
+        self.instr("mov %rsp, %rbp");
+        self.instr("push %rbx");
+        self.instr("push %r12");
+        self.instr("push %r13");
+        self.instr("sub $8, %rsp");
+
+        match self.options.tape_storage {
+            TapeStorage::Stack => {
+                self.instr(&format!("movabs ${}, %rax", tape_bytes.div_ceil(16) * 16));
+                self.instr("sub %rax, %rsp");
+                self.instr("mov %rsp, %r12");
+                self.instr("mov %rsp, %rdi");
+                self.instr("xor %esi, %esi");
+                self.instr(&format!("movabs ${}, %rdx", tape_bytes));
+                self.instr("call memset@PLT");
+            },
+            TapeStorage::Global => self.instr("lea tape(%rip), %r12"),
+            TapeStorage::Heap => {
+                self.instr(&format!("movabs ${}, %rdi", self.options.tape_size));
+                self.instr(&format!("mov ${}, %esi", self.cell_bytes()));
+                self.instr("call calloc@PLT");
+                self.instr("mov %rax, %r12");
+            },
+            TapeStorage::Mmap => {
+                // Round the tape up to whole pages in %rbx, with the page size in %r13
+                self.instr("mov $30, %edi");
+                self.instr("call sysconf@PLT");
+                self.instr("mov %rax, %r13");
+                self.instr(&format!("movabs ${}, %rax", tape_bytes));
+                self.instr("lea -1(%rax,%r13), %rax");
+                self.instr("xor %edx, %edx");
+                self.instr("div %r13");
+                self.instr("imul %r13, %rax");
+                self.instr("mov %rax, %rbx");
+
+                // Map the tape and a guard page on each side inaccessible, then open up the tape
+                self.instr("lea (%rax,%r13,2), %rsi");
+                self.instr("xor %edi, %edi");
+                self.instr("xor %edx, %edx");
+                self.instr("mov $0x22, %ecx");
+                self.instr("mov $-1, %r8d");
+                self.instr("xor %r9d, %r9d");
+                self.instr("call mmap@PLT");
+                self.instr("lea (%rax,%r13), %r12");
+                self.instr("mov %r12, %rdi");
+                self.instr("mov %rbx, %rsi");
+                self.instr("mov $3, %edx");
+                self.instr("call mprotect@PLT");
+            }
+        }
+
+        self.instr("mov %r12, %rbx");
+        self.instr(&format!("movabs ${}, %r13", tape_bytes));
+    }
+
+    /// Calls the out of bounds handler if the cell at `offset` from the head is not on the tape, where `BoundsChecks`
+    /// asks for a check
+    fn check_bounds(&mut self, offset: isize) {
+        if !self.checks.needed(offset) {
+            return;
+        }
+
+        let ok = self.new_label("inbounds");
+        // Cells left of the tape have a negative index, which is huge when compared unsigned
+        let cell = self.cell(offset);
+        self.instr(&format!("lea {}, %rcx", cell));
+        self.instr("sub %r12, %rcx");
+        self.instr("cmp %r13, %rcx");
+        self.instr(&format!("jb {}", ok));
+        self.call_out_of_bounds();
+        self.label(&ok);
+    }
+
+    /// Calls the out of bounds handler with the position `BoundsChecks` reports
+    fn call_out_of_bounds(&mut self) {
+        let position = self.checks.position();
+        self.instr(&format!("movabs ${}, %rdi", position.line));
+        self.instr(&format!("movabs ${}, %rsi", position.column));
+        self.instr("call rustfuck_out_of_bounds");
+    }
+
+    fn move_head(&mut self, amount: isize) {
+        self.check_bounds(amount);
+        self.instr(&format!("add ${}, %rbx", amount * self.cell_bytes() as isize));
+        self.checks.moved(amount);
+    }
+
+    fn add_to_cell(&mut self, offset: isize, amount: i64) {
+        self.check_bounds(offset);
+        let cell = self.cell(offset);
+        match self.options.cell_bits {
+            // Immediates are at most 32 bits, even for 64-bit operands
+            64 if i32::try_from(amount).is_err() => {
+                self.instr(&format!("movabs ${}, %rax", amount));
+                self.instr(&format!("add %rax, {}", cell));
+            },
+            64 => self.instr(&format!("addq ${}, {}", amount, cell)),
+            bits => {
+                let amount = amount as u64 & ((1 << bits) - 1);
+                self.instr(&format!("add{} ${}, {}", self.suffix(), amount, cell));
+            }
+        }
+    }
+
+    fn set_zero(&mut self, offset: isize) {
+        self.check_bounds(offset);
+        let cell = self.cell(offset);
+        self.instr(&format!("mov{} $0, {}", self.suffix(), cell));
+    }
+
+    fn read(&mut self, offset: isize) {
+        self.check_bounds(offset);
+        let cell = self.cell(offset);
+        let eof = self.new_label("eof");
+        let done = self.new_label("endread");
+        self.instr("call rustfuck_getchar");
+        self.instr("test %eax, %eax");
+        self.instr(&format!("js {}", eof));
+        self.instr("movzbl %al, %eax");
+        self.instr(&format!("mov {}, {}", self.rax(), cell));
+        self.instr(&format!("jmp {}", done));
+        self.label(&eof);
+        match self.options.eof {
+            EofBehavior::Zero => self.instr(&format!("mov{} $0, {}", self.suffix(), cell)),
+            EofBehavior::MinusOne => self.instr(&format!("mov{} $-1, {}", self.suffix(), cell)),
+            EofBehavior::Unchanged => ()
+        }
+        self.label(&done);
+    }
+
+    fn write(&mut self, offset: isize) {
+        self.check_bounds(offset);
+        let cell = self.cell(offset);
+        self.instr(&format!("movzbl {}, %edi", cell));
+        self.instr("call putchar@PLT");
+    }
+
+    /// Writes a constant string with `fwrite`, keeping it in order with the rest of the output
+    fn output(&mut self, bytes: &[u8]) {
+        let string = self.new_label("output");
+        self.instr(".section .rodata");
+        self.label(&string);
+        self.instr(&format!(".ascii {}", string_literal(bytes)));
+        self.instr(".text");
+
+        self.instr(&format!("lea {}(%rip), %rdi", string));
+        self.instr("mov $1, %esi");
+        self.instr(&format!("mov ${}, %rdx", bytes.len()));
+        self.instr("mov stdout@GOTPCREL(%rip), %rcx");
+        self.instr("mov (%rcx), %rcx");
+        self.instr("call fwrite@PLT");
+    }
+
+    /// Loads the current cell, zero-extended, into `%rax`
+    fn load_cell(&mut self) {
+        match self.options.cell_bits {
+            8 => self.instr("movzbl (%rbx), %eax"),
+            16 => self.instr("movzwl (%rbx), %eax"),
+            32 => self.instr("movl (%rbx), %eax"),
+            _ => self.instr("movq (%rbx), %rax")
+        }
+    }
+
+    fn generate_scan(&mut self, step: isize) {
+        self.checks.forget();
+        if step == 1 && self.options.cell_bits == 8 {
+            self.generate_memchr_scan();
+            return;
+        }
+
+        let scan = self.new_label("scan");
+        let end = self.new_label("endscan");
+
+        self.label(&scan);
+        self.instr(&format!("cmp{} $0, (%rbx)", self.suffix()));
+        self.instr(&format!("je {}", end));
+        self.move_head(step);
+        self.instr(&format!("jmp {}", scan));
+        self.label(&end);
+    }
+
+    /// Finds the next zero byte with `memchr`, up to the end of the tape
+    fn generate_memchr_scan(&mut self) {
+        self.instr("mov %rbx, %rdi");
+        self.instr("xor %esi, %esi");
+        self.instr("lea (%r12,%r13), %rdx");
+        self.instr("sub %rbx, %rdx");
+        self.instr("call memchr@PLT");
+        if self.options.checked {
+            // No zero before the end of the tape
+            let ok = self.new_label("inbounds");
+            self.instr("test %rax, %rax");
+            self.instr(&format!("jnz {}", ok));
+            self.call_out_of_bounds();
+            self.label(&ok);
+        }
+        self.instr("mov %rax, %rbx");
+    }
+}
+
+impl Backend for AsmGenerator {
+    type Output = String;
+
+    fn generate(&mut self, instructions: &[Instruction]) {
+        for instr in instructions {
+            self.checks.next_instruction(instr);
+
+            match instr {
+                Instruction::IncrementPointer(_) => self.move_head(1),
+                Instruction::DecrementPointer(_) => self.move_head(-1),
+                Instruction::Move { amount, .. } => self.move_head(*amount),
+                Instruction::Increment => self.add_to_cell(0, 1),
+                Instruction::Decrement => self.add_to_cell(0, -1),
+                Instruction::Add(amount) => self.add_to_cell(0, *amount),
+                Instruction::AddAt { offset, amount, .. } => self.add_to_cell(*offset, *amount),
+                Instruction::SetZero => self.set_zero(0),
+                Instruction::SetZeroAt { offset, .. } => self.set_zero(*offset),
+                Instruction::ScanRight(_) => self.generate_scan(1),
+                Instruction::ScanLeft(_) => self.generate_scan(-1),
+                Instruction::MulAdd { offset, factor, .. } => {
+                    self.load_cell();
+                    let skip = self.new_label("skipmul");
+                    self.instr("test %rax, %rax");
+                    self.instr(&format!("jz {}", skip));
+                    self.check_bounds(*offset);
+                    match i32::try_from(*factor) {
+                        Ok(factor) => self.instr(&format!("imul ${}, %rax, %rax", factor)),
+                        Err(_) => {
+                            self.instr(&format!("movabs ${}, %rcx", factor));
+                            self.instr("imul %rcx, %rax");
+                        }
+                    }
+                    let target = self.cell(*offset);
+                    self.instr(&format!("add {}, {}", self.rax(), target));
+                    self.label(&skip);
+                },
+                Instruction::Read => self.read(0),
+                Instruction::ReadAt { offset, .. } => self.read(*offset),
+                Instruction::Write => self.write(0),
+                Instruction::WriteAt { offset, .. } => self.write(*offset),
+                Instruction::Output(bytes) => self.output(bytes),
+                Instruction::Loop(nested_instructions) => {
+                    // Test at the bottom, so that each iteration takes a single branch
+                    let body = self.new_label("loop");
+                    let cond = self.new_label("loopcond");
+                    self.checks.enter_loop(nested_instructions);
+                    self.instr(&format!("jmp {}", cond));
+                    self.label(&body);
+                    self.generate(nested_instructions);
+                    self.label(&cond);
+                    self.instr(&format!("cmp{} $0, (%rbx)", self.suffix()));
+                    self.instr(&format!("jne {}", body));
+                    self.checks.leave_loop(nested_instructions);
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.instr("xor %edi, %edi");
+        self.instr("call fflush@PLT");
+        self.instr("xor %eax, %eax");
+        self.instr("lea -24(%rbp), %rsp");
+        self.instr("pop %r13");
+        self.instr("pop %r12");
+        self.instr("pop %rbx");
+        self.instr("pop %rbp");
+        self.instr("ret");
+        self.instr(".size main, .-main");
+        self.instr(".section .note.GNU-stack,\"\",@progbits");
+
+        self.code
+    }
+}
+
+/// Generates x86-64 assembly for the instructions, for Linux with libc
+pub(crate) fn generate_asm(instructions: &[Instruction], options: &CompileOptions) -> Result<String, String> {
+    let supported = match &options.target {
+        Some(triple) => triple.starts_with("x86_64") && triple.contains("linux"),
+        None => cfg!(all(target_arch = "x86_64", target_os = "linux"))
+    };
+    if !supported {
+        return Err("the x86-64 backend only generates code for x86_64 Linux".to_string());
+    }
+    if options.no_libc {
+        return Err("the x86-64 backend always uses libc for I/O, it can't build without it".to_string());
+    }
+
+    let mut generator = AsmGenerator::new(options);
+    generator.generate(instructions);
+    Ok(generator.finish())
+}
+
+/// Writes the assembly to `path`, or to a temporary file that the system C compiler assembles and links
+/// for objects and executables, which can be overridden with the `CC` environment variable
+pub(crate) fn write_asm(asm: &str, kind: OutputKind, path: &Path) -> Result<(), Error> {
+    let compiler_flags: &[&str] = match kind {
+        OutputKind::Assembly => return std::fs::write(path, asm).map_err(Error::Io),
+        OutputKind::Object => &["-c"],
+        OutputKind::Executable => &[],
+        _ => return Err(Error::Unsupported("the x86-64 backend can only emit assembly, objects and executables".to_string()))
+    };
+
+    let asm_path = temp_path("s");
+    std::fs::write(&asm_path, asm).map_err(Error::Io)?;
+
+    let compiler = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let result = match Command::new(&compiler).args(compiler_flags).arg("-o").arg(path).arg(&asm_path).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::Link(format!("{} exited with {}", compiler, status))),
+        Err(error) => Err(Error::Link(format!("could not run {}: {}", compiler, error)))
+    };
+    let _ = std::fs::remove_file(&asm_path);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_source;
+
+    fn checked_asm(source: &str) -> String {
+        let options = CompileOptions { checked: true, target: Some("x86_64-unknown-linux-gnu".to_string()), ..CompileOptions::default() };
+        generate_asm(&parse_source(source).unwrap(), &options).unwrap()
+    }
+
+    #[test]
+    fn provably_in_bounds_accesses_are_not_checked() {
+        // The head only ever visits the first three cells
+        assert!(!checked_asm(",>+>,<<[->+<]>.").contains("call rustfuck_out_of_bounds"));
+    }
+
+    #[test]
+    fn scans_are_checked_with_their_position() {
+        let code = checked_asm(",[>]<.");
+        assert!(code.contains("call memchr@PLT"));
+        assert!(code.contains("\tmovabs $1, %rdi\n\tmovabs $3, %rsi\n\tcall rustfuck_out_of_bounds\n"));
+    }
+
+    #[test]
+    fn other_targets_are_rejected() {
+        let options = CompileOptions { target: Some("aarch64-unknown-linux-gnu".to_string()), ..CompileOptions::default() };
+        assert!(generate_asm(&[], &options).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{analysis::HeadTracker, CompileOptions, Instruction, Position};
+
+/// A code generator walking the instruction tree
+pub(crate) trait Backend {
+    /// What the backend produces for a whole program
+    type Output;
+
+    /// Generates code for a sequence of instructions at the current position, recursing into loops
+    fn generate(&mut self, instructions: &[Instruction]);
+
+    /// Ends the program, flushing its output, and returns the generated code
+    fn finish(self) -> Self::Output;
+}
+
+/// Decides which bounds checks a backend emits in checked mode, and which source position they report
+pub(crate) struct BoundsChecks {
+    checked: bool,
+    /// Source position of the instruction being generated, or of the last one before it that has one
+    position: Position,
+    /// Statically known head position, which makes some checks unnecessary
+    head: HeadTracker
+}
+
+impl BoundsChecks {
+    pub fn new(options: &CompileOptions) -> Self {
+        // Nothing before the first instruction with a position can leave the tape
+        let position = Position { line: 1, column: 1 };
+        BoundsChecks { checked: options.checked, position, head: HeadTracker::new(options.tape_size) }
+    }
+
+    /// Moves on to the next instruction
+    pub fn next_instruction(&mut self, instr: &Instruction) {
+        if let Some(position) = instr.position() {
+            self.position = position;
+        }
+    }
+
+    /// Source position a failing check reports
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Whether the cell at `offset` from the head, about to be accessed or moved to, needs checking.
+    ///
+    /// The head itself never does, as backends check every move before making it.
+    pub fn needed(&self, offset: isize) -> bool {
+        self.checked && offset != 0 && !self.head.in_bounds(offset)
+    }
+
+    pub fn moved(&mut self, amount: isize) {
+        self.head.moved(amount);
+    }
+
+    /// Forgets the head position, after the head moved by an unknown amount
+    pub fn forget(&mut self) {
+        self.head.forget();
+    }
+
+    pub fn enter_loop(&mut self, body: &[Instruction]) {
+        self.head.enter_loop(body);
+    }
+
+    pub fn leave_loop(&mut self, body: &[Instruction]) {
+        self.head.leave_loop(body);
+    }
+}
+
+/// Quotes bytes as a string literal that both C and the GNU assembler understand
+pub(crate) fn string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'\n' => literal.push_str("\\n"),
+            // `?` is escaped too, so that C doesn't read trigraphs
+            b'"' | b'\\' | b'?' => literal.push_str(&format!("\\{:03o}", byte)),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => literal.push_str(&format!("\\{:03o}", byte))
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex, parse};
+
+    fn checks(tape_size: u64) -> BoundsChecks {
+        BoundsChecks::new(&CompileOptions { checked: true, tape_size, ..CompileOptions::default() })
+    }
+
+    #[test]
+    fn nothing_is_checked_without_checked() {
+        let checks = BoundsChecks::new(&CompileOptions { tape_size: 4, ..CompileOptions::default() });
+        assert!(!checks.needed(-1));
+        assert!(!checks.needed(4));
+    }
+
+    #[test]
+    fn only_cells_that_can_be_off_the_tape_are_checked() {
+        let mut checks = checks(4);
+        assert!(!checks.needed(3));
+        assert!(checks.needed(4));
+        assert!(checks.needed(-1));
+
+        checks.moved(2);
+        assert!(!checks.needed(-2));
+        assert!(checks.needed(2));
+
+        checks.forget();
+        assert!(!checks.needed(0));
+        assert!(checks.needed(1));
+    }
+
+    #[test]
+    fn only_unbalanced_loops_lose_the_head() {
+        let mut checks = checks(4);
+        let balanced = parse(lex(">+<-")).unwrap();
+        checks.enter_loop(&balanced);
+        checks.leave_loop(&balanced);
+        assert!(!checks.needed(1));
+
+        let unbalanced = parse(lex(">,")).unwrap();
+        checks.enter_loop(&unbalanced);
+        assert!(checks.needed(1));
+    }
+}
@@ -0,0 +1,285 @@
+use crate::{backend::{string_literal, Backend, BoundsChecks}, CompileOptions, EofBehavior, Instruction, TapeStorage};
+
+/// Generates portable C, with the tape as an array of fixed-width cells and the head as the pointer `p`
+struct CGenerator {
+    /// Code of `main`'s body, until `finish` puts the headers and helpers in front of it
+    code: String,
+    /// Nesting depth of the statements being generated, `main`'s body being 1
+    indent: usize,
+    options: CompileOptions,
+    checks: BoundsChecks,
+    /// Whether the program reads input, and so needs `read_cell`
+    reads: bool,
+    /// Whether the program checks bounds anywhere, and so needs `out_of_bounds`
+    checks_bounds: bool
+}
+
+impl CGenerator {
+    fn new(options: &CompileOptions) -> Self {
+        CGenerator {
+            code: String::new(),
+            indent: 1,
+            options: options.clone(),
+            checks: BoundsChecks::new(options),
+            reads: false,
+            checks_bounds: false
+        }
+    }
+
+    /// Writes the headers, helpers and the start of `main` with the tape setup `options` call for
+    fn prelude(&mut self) {
+        let options = self.options.clone();
+
+        self.line("#include <stdint.h>");
+        self.line("#include <stdio.h>");
+        self.line("#include <stdlib.h>");
+        self.line("#include <string.h>");
+        if let TapeStorage::Mmap = options.tape_storage {
+            self.line("#include <sys/mman.h>");
+            self.line("#include <unistd.h>");
+        }
+        self.line("");
+        self.line(&format!("typedef uint{}_t cell;", options.cell_bits));
+        self.line(&format!("#define TAPE_SIZE ((size_t){})", options.tape_size));
+        self.line("");
+
+        if let TapeStorage::Global = options.tape_storage {
+            self.line("static cell tape[TAPE_SIZE];");
+            self.line("");
+        }
+
+        if self.checks_bounds {
+            self.line("static void out_of_bounds(unsigned long line, unsigned long column) {");
+            self.line("    fflush(stdout);");
+            self.line("    fprintf(stderr, \"rustfuck: tape head out of bounds at %lu:%lu\\n\", line, column);");
+            self.line("    exit(1);");
+            self.line("}");
+            self.line("");
+        }
+
+        if self.reads {
+            // Flush so that prompts are visible, like the LLVM runtime does
+            self.line("static void read_cell(cell *p) {");
+            self.line("    int c;");
+            self.line("    fflush(stdout);");
+            self.line("    c = getchar();");
+            self.line("    if (c != EOF)");
+            self.line("        *p = (cell)c;");
+            match options.eof {
+                EofBehavior::Zero => self.line("    else\n        *p = 0;"),
+                EofBehavior::MinusOne => self.line("    else\n        *p = (cell)-1;"),
+                EofBehavior::Unchanged => ()
+            }
+            self.line("}");
+            self.line("");
+        }
+
+        self.line("int main(void) {");
+        self.indent = 1;
+        match options.tape_storage {
+            TapeStorage::Stack => self.line("cell tape[TAPE_SIZE] = {0};"),
+            TapeStorage::Global => (),
+            TapeStorage::Heap => self.line("cell *tape = calloc(TAPE_SIZE, sizeof(cell));"),
+            TapeStorage::Mmap => {
+                // Round the tape up to whole pages, and surround it with an inaccessible page on each side
+                self.line("size_t page_size = (size_t)sysconf(_SC_PAGESIZE);");
+                self.line("size_t tape_pages = (TAPE_SIZE * sizeof(cell) + page_size - 1) / page_size * page_size;");
+                self.line("char *mapping = mmap(NULL, tape_pages + 2 * page_size, PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);");
+                self.line("cell *tape = (cell *)(mapping + page_size);");
+                self.line("mprotect(tape, tape_pages, PROT_READ | PROT_WRITE);");
+            }
+        }
+        self.line("cell *p = tape;");
+        self.line("");
+    }
+
+    fn line(&mut self, line: &str) {
+        if !line.is_empty() {
+            self.code.push_str(&"    ".repeat(self.indent));
+        }
+        self.code.push_str(line);
+        self.code.push('\n');
+    }
+
+    /// Emits a check that the cell at `offset` from the head is on the tape, where `BoundsChecks` asks for one
+    fn check_bounds(&mut self, offset: isize) {
+        if self.checks.needed(offset) {
+            // Computing the index keeps the check free of out of bounds pointer arithmetic
+            let condition = format!("(size_t)(p - tape + {}) >= TAPE_SIZE", offset);
+            self.fail_if(&condition);
+        }
+    }
+
+    /// Emits a call to `out_of_bounds` with the position `BoundsChecks` reports, if `condition` holds
+    fn fail_if(&mut self, condition: &str) {
+        self.checks_bounds = true;
+        let position = self.checks.position();
+        self.line(&format!("if ({}) out_of_bounds({}, {});", condition, position.line, position.column));
+    }
+
+    fn move_head(&mut self, amount: isize) {
+        self.check_bounds(amount);
+        self.line(&format!("p += {};", amount));
+        self.checks.moved(amount);
+    }
+
+    /// Expression for the cell at `offset` from the head
+    fn cell(offset: isize) -> String {
+        match offset {
+            0 => "*p".to_string(),
+            _ => format!("p[{}]", offset)
+        }
+    }
+
+    fn add_to_cell(&mut self, offset: isize, amount: i64) {
+        self.check_bounds(offset);
+        let cell = Self::cell(offset);
+        match amount {
+            1 => self.line(&format!("++{};", cell)),
+            -1 => self.line(&format!("--{};", cell)),
+            _ if amount < 0 => self.line(&format!("{} -= {};", cell, amount.unsigned_abs())),
+            _ => self.line(&format!("{} += {};", cell, amount))
+        }
+    }
+
+    fn set_zero(&mut self, offset: isize) {
+        self.check_bounds(offset);
+        self.line(&format!("{} = 0;", Self::cell(offset)));
+    }
+
+    fn read(&mut self, offset: isize) {
+        self.check_bounds(offset);
+        self.reads = true;
+        match offset {
+            0 => self.line("read_cell(p);"),
+            _ => self.line(&format!("read_cell(p + {});", offset))
+        }
+    }
+
+    fn write(&mut self, offset: isize) {
+        self.check_bounds(offset);
+        self.line(&format!("putchar((unsigned char){});", Self::cell(offset)));
+    }
+
+    fn generate_scan(&mut self, step: isize) {
+        self.checks.forget();
+        if step == 1 && self.options.cell_bits == 8 {
+            // memchr finds the zero much faster than a loop, but stops at the end of the tape
+            self.line("p = memchr(p, 0, TAPE_SIZE - (size_t)(p - tape));");
+            if self.options.checked {
+                self.fail_if("!p");
+            }
+            return;
+        }
+
+        match self.options.checked {
+            true => {
+                self.line("while (*p) {");
+                self.indent += 1;
+                self.move_head(step);
+                self.indent -= 1;
+                self.line("}");
+            },
+            false => self.line(if step > 0 { "while (*p) p++;" } else { "while (*p) p--;" })
+        }
+    }
+}
+
+impl Backend for CGenerator {
+    type Output = String;
+
+    fn generate(&mut self, instructions: &[Instruction]) {
+        for instr in instructions {
+            self.checks.next_instruction(instr);
+
+            match instr {
+                Instruction::IncrementPointer(_) => self.move_head(1),
+                Instruction::DecrementPointer(_) => self.move_head(-1),
+                Instruction::Move { amount, .. } => self.move_head(*amount),
+                Instruction::Increment => self.add_to_cell(0, 1),
+                Instruction::Decrement => self.add_to_cell(0, -1),
+                Instruction::Add(amount) => self.add_to_cell(0, *amount),
+                Instruction::AddAt { offset, amount, .. } => self.add_to_cell(*offset, *amount),
+                Instruction::SetZero => self.set_zero(0),
+                Instruction::SetZeroAt { offset, .. } => self.set_zero(*offset),
+                Instruction::ScanRight(_) => self.generate_scan(1),
+                Instruction::ScanLeft(_) => self.generate_scan(-1),
+                Instruction::MulAdd { offset, factor, .. } => {
+                    // Multiply in 64 bits, as narrow cells would be promoted to a signed int that can overflow
+                    self.line("if (*p) {");
+                    self.indent += 1;
+                    self.check_bounds(*offset);
+                    self.line(&format!("p[{}] += (cell)(*p * (uint64_t){});", offset, factor));
+                    self.indent -= 1;
+                    self.line("}");
+                },
+                Instruction::Read => self.read(0),
+                Instruction::ReadAt { offset, .. } => self.read(*offset),
+                Instruction::Write => self.write(0),
+                Instruction::WriteAt { offset, .. } => self.write(*offset),
+                Instruction::Output(bytes) => self.line(&format!("fwrite({}, 1, {}, stdout);", string_literal(bytes), bytes.len())),
+                Instruction::Loop(nested_instructions) => {
+                    self.checks.enter_loop(nested_instructions);
+                    self.line("while (*p) {");
+                    self.indent += 1;
+                    self.generate(nested_instructions);
+                    self.indent -= 1;
+                    self.line("}");
+                    self.checks.leave_loop(nested_instructions);
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> String {
+        let body = std::mem::take(&mut self.code);
+        self.indent = 0;
+        self.prelude();
+        self.code.push_str(&body);
+
+        self.line("");
+        self.line("fflush(stdout);");
+        self.line("return 0;");
+        self.indent = 0;
+        self.line("}");
+
+        self.code
+    }
+}
+
+/// Generates a C program equivalent to the instructions.
+///
+/// The C code relies on the standard library for its I/O, so it can't honor `options.no_libc`.
+pub(crate) fn generate_c(instructions: &[Instruction], options: &CompileOptions) -> Result<String, String> {
+    if options.no_libc {
+        return Err("C output always uses the C standard library, it can't be built without libc".to_string());
+    }
+
+    let mut generator = CGenerator::new(options);
+    generator.generate(instructions);
+    Ok(generator.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_source;
+
+    fn checked_c(source: &str) -> String {
+        let options = CompileOptions { checked: true, ..CompileOptions::default() };
+        generate_c(&parse_source(source).unwrap(), &options).unwrap()
+    }
+
+    #[test]
+    fn provably_in_bounds_accesses_are_not_checked() {
+        // The head only ever visits the first three cells
+        assert!(!checked_c(",>+>,<<[->+<]>.").contains("out_of_bounds"));
+    }
+
+    #[test]
+    fn accesses_after_a_scan_are_checked_with_their_position() {
+        let code = checked_c(",[<]>.");
+        assert!(code.contains("out_of_bounds(1, 3);"));
+        assert!(code.contains("if ((size_t)(p - tape + 1) >= TAPE_SIZE) out_of_bounds(1, 5);"));
+    }
+}
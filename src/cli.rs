@@ -0,0 +1,231 @@
+use std::path::{Path, PathBuf};
+
+use rustfuck::{CodegenBackend, CompileOptions, DumpStage, EofBehavior, OutputKind, TapeStorage};
+
+const HELP: &str = "\
+Usage: rustfuck [OPTIONS] <file.bf>
+
+Compiles a Brainfuck program. Pass `-` as the file to read the program from stdin.
+
+Options:
+  -o <path>              Write the output to <path> (defaults to the input name with
+                         an extension matching --emit, or `out` when reading stdin)
+  --emit <kind>          Kind of output: llvm-ir, bitcode, asm, obj, exe, wasm for WASI
+                         runtimes, or c (defaults to the extension of -o, or llvm-ir)
+  --target <triple>      Generate code for <triple> instead of the host
+  --backend <backend>    Code generator: llvm, or x86-64 for fast compilation without
+                         LLVM (default llvm)
+  -O<level>              Optimization level from 0 to 3 (-O alone means -O2, default -O0)
+  --tape-size <cells>    Number of cells on the tape (default 1024)
+  --tape-storage <kind>  Where to allocate the tape: stack, global, heap, or mmap for
+                         a tape between guard pages on Linux (default stack)
+  --cell-bits <bits>     Width of a tape cell: 8, 16, 32 or 64 (default 8)
+  --eof <behavior>       What `,` stores at end of input: zero, minus-one or unchanged
+                         (default minus-one)
+  --checked              Abort with an error when the tape head leaves the tape
+  --outline-loops <size> Generate loops of at least <size> instructions as separate
+                         functions, which makes LLVM much faster on huge programs
+  --no-libc              Make Linux system calls directly instead of using libc, for
+                         small static executables (x86_64 and aarch64 only)
+  --run                  Execute the program with the built-in interpreter
+  --jit                  Compile the program in memory and execute it right away
+  --dump <stage>         Print a stage of the compiler instead of compiling: lex, ast,
+                         opt-ast or llvm
+  --stats                Report the number of instructions before and after each
+                         optimization pass
+  -h, --help             Print this help
+";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Mode {
+    Compile,
+    Interpret,
+    Jit,
+    Dump(DumpStage)
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub mode: Mode,
+    /// Path of the program, `-` meaning stdin
+    pub input: String,
+    pub output: Option<PathBuf>,
+    pub emit: Option<OutputKind>,
+    /// Whether to report what each optimization pass did
+    pub stats: bool,
+    pub compile: CompileOptions
+}
+
+impl Options {
+    /// The kind of output to emit, from `--emit`, the extension of `-o`, or the backend's readable output:
+    /// LLVM IR, assembly for x86-64, or C without LLVM
+    pub fn output_kind(&self) -> OutputKind {
+        match (self.emit, &self.output, self.compile.backend) {
+            (Some(kind), _, _) => kind,
+            (None, Some(output), _) => OutputKind::from_path(output),
+            (None, None, CodegenBackend::X86_64) => OutputKind::Assembly,
+            (None, None, CodegenBackend::Llvm) if cfg!(feature = "llvm") => OutputKind::LlvmIr,
+            (None, None, CodegenBackend::Llvm) => OutputKind::C
+        }
+    }
+
+    /// The path to write to, derived from the input file name unless `-o` was given
+    pub fn output_path(&self) -> PathBuf {
+        if let Some(output) = &self.output {
+            return output.clone();
+        }
+
+        let stem = match self.input.as_str() {
+            "-" => PathBuf::from("out"),
+            input => PathBuf::from(input).file_stem().map(PathBuf::from).unwrap_or_else(|| PathBuf::from("out"))
+        };
+
+        let output = match self.output_kind().extension() {
+            Some(extension) => stem.with_extension(extension),
+            None => stem
+        };
+
+        // Never overwrite the program itself, e.g. when building an executable from `prog`
+        if output == Path::new(&self.input) {
+            output.with_extension("out")
+        } else {
+            output
+        }
+    }
+}
+
+fn parse_emit(kind: &str) -> Result<OutputKind, String> {
+    match kind {
+        "llvm-ir" => Ok(OutputKind::LlvmIr),
+        "bitcode" => Ok(OutputKind::Bitcode),
+        "asm" => Ok(OutputKind::Assembly),
+        "obj" => Ok(OutputKind::Object),
+        "exe" => Ok(OutputKind::Executable),
+        "wasm" => Ok(OutputKind::Wasm),
+        "c" => Ok(OutputKind::C),
+        _ => Err(format!("unknown emit kind `{}`, expected one of llvm-ir, bitcode, asm, obj, exe, wasm or c", kind))
+    }
+}
+
+fn parse_dump(stage: &str) -> Result<DumpStage, String> {
+    match stage {
+        "lex" => Ok(DumpStage::Lex),
+        "ast" => Ok(DumpStage::Ast),
+        "opt-ast" => Ok(DumpStage::OptAst),
+        "llvm" => Ok(DumpStage::Llvm),
+        _ => Err(format!("unknown stage `{}`, expected one of lex, ast, opt-ast or llvm", stage))
+    }
+}
+
+fn parse_backend(backend: &str) -> Result<CodegenBackend, String> {
+    match backend {
+        "llvm" => Ok(CodegenBackend::Llvm),
+        "x86-64" => Ok(CodegenBackend::X86_64),
+        _ => Err(format!("unknown backend `{}`, expected llvm or x86-64", backend))
+    }
+}
+
+fn parse_tape_size(size: &str) -> Result<u64, String> {
+    // LLVM array types, used for global tapes, are limited to 32-bit lengths
+    match size.parse::<u32>() {
+        Ok(0) | Err(_) => Err(format!("invalid tape size `{}`, expected a number of cells between 1 and {}", size, u32::MAX)),
+        Ok(size) => Ok(size as u64)
+    }
+}
+
+fn parse_tape_storage(kind: &str) -> Result<TapeStorage, String> {
+    match kind {
+        "stack" => Ok(TapeStorage::Stack),
+        "global" => Ok(TapeStorage::Global),
+        "heap" => Ok(TapeStorage::Heap),
+        "mmap" => Ok(TapeStorage::Mmap),
+        _ => Err(format!("unknown tape storage `{}`, expected stack, global, heap or mmap", kind))
+    }
+}
+
+fn parse_cell_bits(bits: &str) -> Result<u32, String> {
+    match bits {
+        "8" | "16" | "32" | "64" => Ok(bits.parse().unwrap()),
+        _ => Err(format!("unsupported cell width `{}`, expected 8, 16, 32 or 64", bits))
+    }
+}
+
+fn parse_eof(behavior: &str) -> Result<EofBehavior, String> {
+    match behavior {
+        "zero" => Ok(EofBehavior::Zero),
+        "minus-one" => Ok(EofBehavior::MinusOne),
+        "unchanged" => Ok(EofBehavior::Unchanged),
+        _ => Err(format!("unknown EOF behavior `{}`, expected zero, minus-one or unchanged", behavior))
+    }
+}
+
+fn parse_outline_threshold(size: &str) -> Result<usize, String> {
+    match size.parse() {
+        Ok(0) | Err(_) => Err(format!("invalid loop size `{}`, expected a positive number of instructions", size)),
+        Ok(size) => Ok(size)
+    }
+}
+
+fn parse_opt_level(level: &str) -> Result<u8, String> {
+    match level {
+        "" => Ok(2),
+        "0" | "1" | "2" | "3" => Ok(level.parse().unwrap()),
+        _ => Err(format!("unknown optimization level `-O{}`, expected -O0 to -O3", level))
+    }
+}
+
+/// Parses the command line arguments, without the program name.
+///
+/// Prints the help and exits if it is requested.
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        mode: Mode::Compile,
+        input: String::new(),
+        output: None,
+        emit: None,
+        stats: false,
+        compile: CompileOptions::default()
+    };
+    let mut input = None;
+
+    while let Some(arg) = args.next() {
+        // Options taking a value accept both `--option value` and `--option=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None)
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next()).ok_or_else(|| format!("missing value for `{}`", flag));
+
+        match flag.as_str() {
+            "--help" | "--run" | "--jit" | "--stats" | "--checked" | "--no-libc" if inline_value.is_some() => {
+                return Err(format!("option `{}` takes no value", flag));
+            },
+            "-h" | "--help" => {
+                print!("{}", HELP);
+                std::process::exit(0);
+            },
+            "--run" => options.mode = Mode::Interpret,
+            "--jit" => options.mode = Mode::Jit,
+            "--dump" => options.mode = Mode::Dump(parse_dump(&value()?)?),
+            "--stats" => options.stats = true,
+            "-o" => options.output = Some(PathBuf::from(value()?)),
+            "--emit" => options.emit = Some(parse_emit(&value()?)?),
+            "--target" => options.compile.target = Some(value()?),
+            "--backend" => options.compile.backend = parse_backend(&value()?)?,
+            "--tape-size" => options.compile.tape_size = parse_tape_size(&value()?)?,
+            "--tape-storage" => options.compile.tape_storage = parse_tape_storage(&value()?)?,
+            "--cell-bits" => options.compile.cell_bits = parse_cell_bits(&value()?)?,
+            "--eof" => options.compile.eof = parse_eof(&value()?)?,
+            "--checked" => options.compile.checked = true,
+            "--no-libc" => options.compile.no_libc = true,
+            "--outline-loops" => options.compile.outline_threshold = Some(parse_outline_threshold(&value()?)?),
+            _ if flag.starts_with("-O") => options.compile.opt_level = parse_opt_level(&flag[2..])?,
+            _ if flag.starts_with('-') && flag != "-" => return Err(format!("unknown option `{}`", flag)),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("unexpected argument `{}`, only one input file is supported", arg))
+        }
+    }
+
+    options.input = input.ok_or_else(|| "no input file given".to_string())?;
+    Ok(options)
+}
@@ -0,0 +1,536 @@
+use std::cmp::Ordering;
+
+use inkwell::{attributes::{Attribute, AttributeLoc}, context::Context, AddressSpace, module::{Linkage, Module}, values::{FunctionValue, IntValue, PointerValue}, builder::Builder, IntPredicate, types::{IntType, PointerType}};
+
+use crate::{backend::{Backend, BoundsChecks}, runtime::{build_runtime, build_start, build_system, Platform, Runtime, System, MAP_PRIVATE_ANONYMOUS, PROT_NONE, PROT_READ_WRITE}, instruction_count, CompileOptions, EofBehavior, Instruction, TapeStorage};
+
+struct CommonTypes<'a> {
+    /// Type of a tape cell
+    cell: IntType<'a>,
+    /// Type of the characters passed to and from libc, a C `int`
+    c_int: IntType<'a>,
+    ptr: PointerType<'a>,
+    /// Type of the head, an index into the tape
+    index: IntType<'a>
+}
+
+struct CodeGenContext<'a> {
+    builder: Builder<'a>,
+    context: &'a Context,
+    /// Function currently being generated, `main` unless a loop is being outlined
+    function: FunctionValue<'a>,
+    module: Module<'a>,
+    /// Pointer to the first cell of the tape
+    tape: PointerValue<'a>,
+    /// Index of the cell under the head, as an SSA value merged by phi nodes at loop headers.
+    ///
+    /// Cells are only ever addressed as GEPs from the tape, which keeps the IR easy for LLVM to analyze.
+    head: IntValue<'a>,
+    runtime: Runtime<'a>,
+    common_types: CommonTypes<'a>,
+    options: CompileOptions,
+    /// Reports an out of bounds head and exits, only present in checked mode
+    out_of_bounds_handler: Option<FunctionValue<'a>>,
+    /// libc's `memchr`, which `ScanRight` uses on 8-bit cells when it is available
+    memchr: Option<FunctionValue<'a>>,
+    checks: BoundsChecks
+}
+
+impl<'a> CodeGenContext<'a> {
+    /// Truncates or zero-extends an integer to the given type
+    fn resize_int(&self, value: IntValue<'a>, int_type: IntType<'a>) -> IntValue<'a> {
+        match value.get_type().get_bit_width().cmp(&int_type.get_bit_width()) {
+            Ordering::Less => self.builder.build_int_z_extend(value, int_type, ""),
+            Ordering::Equal => value,
+            Ordering::Greater => self.builder.build_int_truncate(value, int_type, "")
+        }
+    }
+
+    /// Index of the cell at `offset` from the head
+    fn get_cell_index(&self, offset: isize) -> IntValue<'a> {
+        match offset {
+            0 => self.head,
+            _ => self.builder.build_int_add(self.head, self.common_types.index.const_int(offset as u64, true), "")
+        }
+    }
+
+    fn get_cell_ptr(&self, offset: isize) -> PointerValue<'a> {
+        let index = self.get_cell_index(offset);
+        unsafe { self.builder.build_gep(self.tape, &[index], "") }
+    }
+
+    fn get_head_ptr(&self) -> PointerValue<'a> {
+        self.get_cell_ptr(0)
+    }
+
+    /// Branches to the out of bounds handler if the cell at `offset` from the head is not on the tape, where
+    /// `BoundsChecks` asks for a check
+    fn check_bounds(&self, offset: isize) {
+        let handler = match self.out_of_bounds_handler {
+            Some(handler) if self.checks.needed(offset) => handler,
+            _ => return
+        };
+        let index_type = self.common_types.index;
+        let index = self.get_cell_index(offset);
+
+        // Cells left of the tape have a negative index, which is huge when compared unsigned
+        let tape_size = index_type.const_int(self.options.tape_size, false);
+        let out_of_bounds = self.builder.build_int_compare(IntPredicate::UGE, index, tape_size, "");
+        self.fail_if(handler, out_of_bounds);
+    }
+
+    /// Calls the out of bounds handler with the position `BoundsChecks` reports if `condition` holds
+    fn fail_if(&self, handler: FunctionValue<'a>, condition: IntValue<'a>) {
+        let index_type = self.common_types.index;
+        let fail_block = self.context.append_basic_block(self.function, "outofbounds");
+        let ok_block = self.context.append_basic_block(self.function, "inbounds");
+        self.builder.build_conditional_branch(condition, fail_block, ok_block);
+
+        self.builder.position_at_end(fail_block);
+        let position = self.checks.position();
+        let args = [index_type.const_int(position.line as u64, false).into(), index_type.const_int(position.column as u64, false).into()];
+        self.builder.build_call(handler, &args, "");
+        self.builder.build_unreachable();
+
+        self.builder.position_at_end(ok_block);
+    }
+
+    fn move_head(&mut self, amount: isize) {
+        self.check_bounds(amount);
+        self.head = self.get_cell_index(amount);
+        self.checks.moved(amount);
+    }
+
+    /// Pointer to the cell at `offset` from the head, checked to be on the tape if needed
+    fn get_checked_cell_ptr(&self, offset: isize) -> PointerValue<'a> {
+        self.check_bounds(offset);
+        self.get_cell_ptr(offset)
+    }
+
+    fn add_to_cell(&self, offset: isize, amount: i64) {
+        let cell = self.get_checked_cell_ptr(offset);
+        let content = self.builder.build_load(cell, "").into_int_value();
+        let new_content = self.builder.build_int_add(content, self.common_types.cell.const_int(amount as u64, true), "");
+        self.builder.build_store(cell, new_content);
+    }
+
+    fn generate_read(&self, offset: isize) {
+        let cell_type = self.common_types.cell;
+        let cell = self.get_checked_cell_ptr(offset);
+
+        let char = self.builder.build_call(self.runtime.getchar, &[], "").try_as_basic_value().expect_left("getchar call returned no value :(").into_int_value();
+        let is_eof = self.builder.build_int_compare(IntPredicate::SLT, char, self.common_types.c_int.const_zero(), "");
+        let char = self.resize_int(char, cell_type);
+
+        let eof_value = match self.options.eof {
+            EofBehavior::Zero => cell_type.const_zero(),
+            EofBehavior::MinusOne => cell_type.const_all_ones(),
+            EofBehavior::Unchanged => self.builder.build_load(cell, "").into_int_value()
+        };
+        let new_content = self.builder.build_select(is_eof, eof_value, char, "");
+        self.builder.build_store(cell, new_content);
+    }
+
+    /// Outputs the cell at `offset` `count` times
+    fn generate_write(&self, offset: isize, count: u64) {
+        let cell = self.get_checked_cell_ptr(offset);
+        let content = self.builder.build_load(cell, "").into_int_value();
+        let char = self.resize_int(content, self.common_types.c_int);
+        let args = [char.into(), self.context.i64_type().const_int(count, false).into()];
+        self.builder.build_call(self.runtime.putchar, &args, "");
+    }
+
+    /// Outputs a constant string, after whatever is still in the output buffer
+    fn generate_output(&self, bytes: &[u8]) {
+        let i8_type = self.context.i8_type();
+        let chars: Vec<_> = bytes.iter().map(|&byte| i8_type.const_int(byte as u64, false)).collect();
+        let string = self.module.add_global(i8_type.array_type(bytes.len() as u32), None, "output");
+        string.set_linkage(Linkage::Private);
+        string.set_constant(true);
+        string.set_initializer(&i8_type.const_array(&chars));
+
+        let data = string.as_pointer_value().const_cast(i8_type.ptr_type(AddressSpace::Generic));
+        self.builder.build_call(self.runtime.flush, &[], "");
+        let args = [data.into(), self.context.i64_type().const_int(bytes.len() as u64, false).into()];
+        self.builder.build_call(self.runtime.write_all, &args, "");
+    }
+
+    /// Emits a loop running `body` while the current cell is not zero, with a phi node merging the head.
+    ///
+    /// `body` generates code at the current position and leaves `self.head` as the head at the end of an iteration.
+    fn generate_while_nonzero(&mut self, name: &str, body: impl FnOnce(&mut Self)) {
+        let context = self.context;
+
+        let loop_cond = context.append_basic_block(self.function, &format!("{}cond", name));
+        let loop_body = context.append_basic_block(self.function, name);
+        let after_loop = context.append_basic_block(self.function, &format!("end{}", name));
+
+        let before_loop = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(loop_cond);
+        self.builder.position_at_end(loop_cond);
+
+        let head = self.builder.build_phi(self.common_types.index, "head");
+        head.add_incoming(&[(&self.head, before_loop)]);
+        let head_val = head.as_basic_value().into_int_value();
+        self.head = head_val;
+
+        let head_content = self.builder.build_load(self.get_head_ptr(), "").into_int_value();
+        let should_execute = self.builder.build_int_compare(IntPredicate::NE, head_content, self.common_types.cell.const_zero(), "");
+        self.builder.build_conditional_branch(should_execute, loop_body, after_loop);
+
+        self.builder.position_at_end(loop_body);
+        body(self);
+        // The body may have ended in another block than it started in
+        head.add_incoming(&[(&self.head, self.builder.get_insert_block().unwrap())]);
+        self.builder.build_unconditional_branch(loop_cond);
+
+        self.builder.position_at_end(after_loop);
+        self.head = head_val;
+    }
+
+    /// Emits a tight loop stepping the head by `step` until it points at a zero cell
+    fn generate_scan(&mut self, step: isize) {
+        self.checks.forget();
+        match self.memchr {
+            Some(memchr) if step == 1 && self.options.cell_bits == 8 => self.generate_memchr_scan(memchr),
+            _ => self.generate_while_nonzero("scan", |codegen| codegen.move_head(step))
+        }
+    }
+
+    /// Finds the next zero cell with `memchr`, up to the end of the tape
+    fn generate_memchr_scan(&mut self, memchr: FunctionValue<'a>) {
+        let index_type = self.common_types.index;
+        let remaining = self.builder.build_int_sub(index_type.const_int(self.options.tape_size, false), self.head, "");
+        let args = [self.get_head_ptr().into(), self.common_types.c_int.const_zero().into(), remaining.into()];
+        let zero = self.builder.build_call(memchr, &args, "zero").try_as_basic_value().expect_left("memchr call returned no value :(").into_pointer_value();
+
+        if let Some(handler) = self.out_of_bounds_handler {
+            // No zero before the end of the tape
+            let not_found = self.builder.build_is_null(zero, "");
+            self.fail_if(handler, not_found);
+        }
+        self.head = self.builder.build_ptr_diff(zero, self.tape, "");
+    }
+
+    fn generate_loop(&mut self, nested_instructions: &[Instruction]) {
+        self.checks.enter_loop(nested_instructions);
+        self.generate_while_nonzero("loop", |codegen| codegen.generate(nested_instructions));
+        self.checks.leave_loop(nested_instructions);
+    }
+
+    /// Generates the loop in a function of its own, taking the tape and the head and returning the new head.
+    ///
+    /// The function is never inlined, so that LLVM optimizes many small functions instead of one huge `main`.
+    fn generate_outlined_loop(&mut self, nested_instructions: &[Instruction]) {
+        let context = self.context;
+        let index_type = self.common_types.index;
+
+        let function_type = index_type.fn_type(&[self.common_types.ptr.into(), index_type.into()], false);
+        let function = self.module.add_function("loop", function_type, Some(Linkage::Internal));
+        let noinline = context.create_enum_attribute(Attribute::get_named_enum_kind_id("noinline"), 0);
+        function.add_attribute(AttributeLoc::Function, noinline);
+
+        let args = [self.tape.into(), self.head.into()];
+        let new_head = self.builder.build_call(function, &args, "").try_as_basic_value().expect_left("loop call returned no value :(").into_int_value();
+        let caller_block = self.builder.get_insert_block().unwrap();
+
+        // Switch to the new function, with the tape and the head as its parameters
+        self.builder.position_at_end(context.append_basic_block(function, "entry"));
+        let caller = std::mem::replace(&mut self.function, function);
+        let caller_tape = std::mem::replace(&mut self.tape, function.get_nth_param(0).unwrap().into_pointer_value());
+        self.head = function.get_nth_param(1).unwrap().into_int_value();
+
+        self.generate_loop(nested_instructions);
+        self.builder.build_return(Some(&self.head));
+
+        self.function = caller;
+        self.tape = caller_tape;
+        self.head = new_head;
+        self.builder.position_at_end(caller_block);
+    }
+}
+
+/// Offset from the head of the cell a `Write` or `WriteAt` outputs
+fn write_offset(instr: &Instruction) -> Option<isize> {
+    match instr {
+        Instruction::Write => Some(0),
+        Instruction::WriteAt { offset, .. } => Some(*offset),
+        _ => None
+    }
+}
+
+impl<'a> Backend for CodeGenContext<'a> {
+    type Output = Module<'a>;
+
+    fn generate(&mut self, instructions: &[Instruction]) {
+        // Initialize some values
+        let cell_type = self.common_types.cell;
+    
+        let mut instructions = instructions.iter().peekable();
+        while let Some(instr) = instructions.next() {
+            self.checks.next_instruction(instr);
+
+            match instr {
+                Instruction::IncrementPointer(_) => self.move_head(1),
+                Instruction::DecrementPointer(_) => self.move_head(-1),
+                Instruction::Move { amount, .. } => self.move_head(*amount),
+                Instruction::Increment => self.add_to_cell(0, 1),
+                Instruction::Decrement => self.add_to_cell(0, -1),
+                Instruction::Add(amount) => self.add_to_cell(0, *amount),
+                Instruction::AddAt { offset, amount, .. } => self.add_to_cell(*offset, *amount),
+                Instruction::SetZero => {
+                    self.builder.build_store(self.get_head_ptr(), cell_type.const_zero());
+                },
+                Instruction::SetZeroAt { offset, .. } => {
+                    self.builder.build_store(self.get_checked_cell_ptr(*offset), cell_type.const_zero());
+                },
+                Instruction::ScanRight(_) => self.generate_scan(1),
+                Instruction::ScanLeft(_) => self.generate_scan(-1),
+                Instruction::MulAdd { offset, factor, .. } => {
+                    let head_content = self.builder.build_load(self.get_head_ptr(), "").into_int_value();
+
+                    let mul_add = self.context.append_basic_block(self.function, "muladd");
+                    let after_mul_add = self.context.append_basic_block(self.function, "endmuladd");
+                    let is_nonzero = self.builder.build_int_compare(IntPredicate::NE, head_content, cell_type.const_zero(), "");
+                    self.builder.build_conditional_branch(is_nonzero, mul_add, after_mul_add);
+
+                    self.builder.position_at_end(mul_add);
+                    let product = self.builder.build_int_mul(head_content, cell_type.const_int(*factor as u64, true), "");
+                    let target = self.get_checked_cell_ptr(*offset);
+                    let target_content = self.builder.build_load(target, "").into_int_value();
+                    let new_content = self.builder.build_int_add(target_content, product, "");
+                    self.builder.build_store(target, new_content);
+                    self.builder.build_unconditional_branch(after_mul_add);
+
+                    self.builder.position_at_end(after_mul_add);
+                },
+                Instruction::Read => self.generate_read(0),
+                Instruction::ReadAt { offset, .. } => self.generate_read(*offset),
+                Instruction::Write | Instruction::WriteAt { .. } => {
+                    // Consecutive writes of the same cell become a single call
+                    let offset = write_offset(instr).unwrap();
+                    let mut count = 1;
+                    while let Some(next) = instructions.next_if(|next| write_offset(next) == Some(offset)) {
+                        self.checks.next_instruction(next);
+                        count += 1;
+                    }
+
+                    self.generate_write(offset, count);
+                },
+                Instruction::Output(bytes) => self.generate_output(bytes),
+                Instruction::Loop(nested_instructions) => match self.options.outline_threshold {
+                    Some(threshold) if instruction_count(nested_instructions) >= threshold => self.generate_outlined_loop(nested_instructions),
+                    _ => self.generate_loop(nested_instructions)
+                },
+            }
+        }
+    }
+
+    fn finish(self) -> Module<'a> {
+        self.builder.build_call(self.runtime.flush, &[], "");
+        self.builder.build_return(Some(&self.common_types.c_int.const_zero()));
+        self.module
+    }
+}
+
+/// Defines a function printing the source position whose instruction moved the head out of bounds, then exiting.
+///
+/// It only uses `write` and `exit`, formatting the position itself so that it works without libc too.
+fn build_out_of_bounds_handler<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>, system: &System<'ctx>, runtime: &Runtime<'ctx>) -> FunctionValue<'ctx> {
+    const PREFIX: &str = "rustfuck: tape head out of bounds at ";
+
+    let void = context.void_type();
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let stderr = i32_type.const_int(2, false);
+
+    let write_decimal = build_write_decimal(context, module, builder, system);
+
+    let handler_type = void.fn_type(&[i64_type.into(), i64_type.into()], false);
+    let handler = module.add_function("rustfuck_out_of_bounds", handler_type, Some(Linkage::Private));
+    builder.position_at_end(context.append_basic_block(handler, "entry"));
+
+    // Keep the output that came before the error, then write `line:column` straight to stderr
+    builder.build_call(runtime.flush, &[], "");
+    let write_str = |text: &str, name: &str| {
+        let string = builder.build_global_string_ptr(text, name);
+        let args = [stderr.into(), string.as_pointer_value().into(), i64_type.const_int(text.len() as u64, false).into()];
+        builder.build_call(system.write, &args, "");
+    };
+    write_str(PREFIX, "out_of_bounds_message");
+    builder.build_call(write_decimal, &[handler.get_nth_param(0).unwrap().into()], "");
+    write_str(":", "colon");
+    builder.build_call(write_decimal, &[handler.get_nth_param(1).unwrap().into()], "");
+    write_str("\n", "newline");
+    builder.build_call(system.exit, &[i32_type.const_int(1, false).into()], "");
+    builder.build_unreachable();
+
+    handler
+}
+
+/// Defines `void rustfuck_write_decimal(i64 value)`, which writes an unsigned number to stderr
+fn build_write_decimal<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>, system: &System<'ctx>) -> FunctionValue<'ctx> {
+    // Enough for any 64-bit number
+    const MAX_DIGITS: u64 = 20;
+
+    let i8_type = context.i8_type();
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let ten = i64_type.const_int(10, false);
+    let stderr = i32_type.const_int(2, false);
+
+    let function_type = context.void_type().fn_type(&[i64_type.into()], false);
+    let function = module.add_function("rustfuck_write_decimal", function_type, Some(Linkage::Private));
+    let entry = context.append_basic_block(function, "entry");
+    let digit_block = context.append_basic_block(function, "digit");
+    let done = context.append_basic_block(function, "done");
+
+    builder.position_at_end(entry);
+    let digits = builder.build_alloca(i8_type.array_type(MAX_DIGITS as u32), "digits");
+    let digits = builder.build_pointer_cast(digits, i8_type.ptr_type(AddressSpace::Generic), "");
+    builder.build_unconditional_branch(digit_block);
+
+    // Fill the digits from the end, least significant first
+    builder.position_at_end(digit_block);
+    let value = builder.build_phi(i64_type, "value");
+    let start = builder.build_phi(i64_type, "start");
+    value.add_incoming(&[(&function.get_nth_param(0).unwrap(), entry)]);
+    start.add_incoming(&[(&i64_type.const_int(MAX_DIGITS, false), entry)]);
+    let value_val = value.as_basic_value().into_int_value();
+    let new_start = builder.build_int_sub(start.as_basic_value().into_int_value(), i64_type.const_int(1, false), "");
+    let digit = builder.build_int_add(builder.build_int_unsigned_rem(value_val, ten, ""), i64_type.const_int(b'0' as u64, false), "");
+    builder.build_store(unsafe { builder.build_gep(digits, &[new_start], "") }, builder.build_int_truncate(digit, i8_type, ""));
+    let new_value = builder.build_int_unsigned_div(value_val, ten, "");
+    value.add_incoming(&[(&new_value, digit_block)]);
+    start.add_incoming(&[(&new_start, digit_block)]);
+    let is_last = builder.build_int_compare(IntPredicate::EQ, new_value, i64_type.const_zero(), "");
+    builder.build_conditional_branch(is_last, done, digit_block);
+
+    builder.position_at_end(done);
+    let len = builder.build_int_sub(i64_type.const_int(MAX_DIGITS, false), new_start, "");
+    let args = [stderr.into(), unsafe { builder.build_gep(digits, &[new_start], "") }.into(), len.into()];
+    builder.build_call(system.write, &args, "");
+    builder.build_return(None);
+
+    function
+}
+
+/// Allocates the zeroed tape where `options.tape_storage` asks for it, returning a pointer to its first cell
+fn build_tape<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>, system: &System<'ctx>, options: &CompileOptions) -> PointerValue<'ctx> {
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let cell_type = context.custom_width_int_type(options.cell_bits);
+    let byte_ptr_type = context.i8_type().ptr_type(AddressSpace::Generic);
+    let ptr_type = cell_type.ptr_type(AddressSpace::Generic);
+
+    let tape_size = i64_type.const_int(options.tape_size, false);
+    let tape_bytes = i64_type.const_int(options.tape_size * (options.cell_bits as u64 / 8), false);
+
+    match options.tape_storage {
+        TapeStorage::Stack => {
+            let tape = builder.build_array_alloca(cell_type, tape_size, "tape");
+
+            // Zero out the tape, with the runtime's own memset when there is no libc
+            let memset = module.get_function("memset").unwrap_or_else(|| {
+                let param_types = [byte_ptr_type.into(), i32_type.into(), i64_type.into()];
+                module.add_function("memset", byte_ptr_type.fn_type(&param_types, false), None)
+            });
+            let args = [builder.build_pointer_cast(tape, byte_ptr_type, "").into(), i32_type.const_zero().into(), tape_bytes.into()];
+            builder.build_call(memset, &args, "");
+
+            tape
+        },
+        TapeStorage::Global => {
+            let tape_type = cell_type.array_type(options.tape_size as u32);
+            let tape = module.add_global(tape_type, None, "tape");
+            tape.set_linkage(Linkage::Internal);
+            tape.set_initializer(&tape_type.const_zero());
+
+            builder.build_pointer_cast(tape.as_pointer_value(), ptr_type, "")
+        },
+        TapeStorage::Heap => {
+            let tape = builder.build_call(system.alloc_zeroed, &[tape_bytes.into()], "tape")
+                .try_as_basic_value().expect_left("allocation returned no value :(");
+
+            builder.build_pointer_cast(tape.into_pointer_value(), ptr_type, "")
+        },
+        TapeStorage::Mmap => {
+            let page_size = builder.build_call(system.page_size, &[], "page_size")
+                .try_as_basic_value().expect_left("page size call returned no value :(").into_int_value();
+
+            // Round the tape up to whole pages, and surround it with a page on each side
+            let page_mask = builder.build_int_sub(page_size, i64_type.const_int(1, false), "");
+            let tape_pages = builder.build_int_mul(builder.build_int_unsigned_div(builder.build_int_add(tape_bytes, page_mask, ""), page_size, ""), page_size, "");
+            let mapping_size = builder.build_int_add(tape_pages, builder.build_int_mul(page_size, i64_type.const_int(2, false), ""), "");
+
+            // Map everything inaccessible, then open up the pages between the guards
+            let args = [
+                byte_ptr_type.const_null().into(),
+                mapping_size.into(),
+                i32_type.const_int(PROT_NONE, false).into(),
+                i32_type.const_int(MAP_PRIVATE_ANONYMOUS, false).into(),
+                i32_type.const_all_ones().into(),
+                i64_type.const_zero().into()
+            ];
+            // Platform::for_options only accepts mmap tapes on Linux, which always has mmap
+            let (mmap, mprotect) = system.mmap.zip(system.mprotect).expect("no mmap for the tape :(");
+            let mapping = builder.build_call(mmap, &args, "mapping").try_as_basic_value().expect_left("mmap call returned no value :(").into_pointer_value();
+            let tape = unsafe { builder.build_gep(mapping, &[page_size], "tape") };
+            let args = [tape.into(), tape_pages.into(), i32_type.const_int(PROT_READ_WRITE, false).into()];
+            builder.build_call(mprotect, &args, "");
+
+            builder.build_pointer_cast(tape, ptr_type, "")
+        }
+    }
+}
+
+/// Generates the module for a program, failing if the options can't be honored for the target
+pub(crate) fn generate_llvm<'ctx>(context: &'ctx Context, instructions: &[Instruction], options: &CompileOptions) -> Result<Module<'ctx>, String> {
+    let module = context.create_module("rustfuck");
+    
+    let builder = context.create_builder();
+
+    let func_type = context.i32_type().fn_type(&[], false);
+    let main = module.add_function("main", func_type, None);
+
+    let platform = Platform::for_options(options)?;
+    let system = build_system(context, &module, &builder, platform);
+    if platform.defines_start() {
+        build_start(context, &module, &builder, main, &system, platform);
+    }
+    let runtime = build_runtime(context, &module, &builder, &system);
+    let out_of_bounds_handler = match options.checked {
+        true => Some(build_out_of_bounds_handler(context, &module, &builder, &system, &runtime)),
+        false => None
+    };
+
+    let basic_block = context.append_basic_block(main, "entry");
+    builder.position_at_end(basic_block);
+
+    // Initialize types
+    let i32_type = context.i32_type();
+    let cell_type = context.custom_width_int_type(options.cell_bits);
+    let ptr_type = cell_type.ptr_type(AddressSpace::Generic);
+    let index_type = context.i64_type();
+
+    // Initialize the tape, with the head on its first cell
+    let tape = build_tape(context, &module, &builder, &system, options);
+
+    let mut codegen = CodeGenContext{
+        builder,
+        context,
+        function: main,
+        module,
+        tape,
+        head: index_type.const_zero(),
+        runtime,
+        common_types: CommonTypes { cell: cell_type, c_int: i32_type, ptr: ptr_type, index: index_type },
+        options: options.clone(),
+        out_of_bounds_handler,
+        memchr: system.memchr,
+        checks: BoundsChecks::new(options)
+    };
+
+    codegen.generate(instructions);
+    Ok(codegen.finish())
+}
@@ -0,0 +1,138 @@
+use std::{path::Path, process::Command};
+
+use inkwell::{module::Module, passes::{PassManager, PassManagerBuilder}, targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple}, OptimizationLevel};
+
+use crate::{temp_path, CompileOptions, Error, OutputKind};
+
+/// Maps a `-O` level to LLVM's optimization levels
+pub fn llvm_opt_level(level: u8) -> OptimizationLevel {
+    match level {
+        0 => OptimizationLevel::None,
+        1 => OptimizationLevel::Less,
+        2 => OptimizationLevel::Default,
+        _ => OptimizationLevel::Aggressive
+    }
+}
+
+/// Checks that the module is valid, then runs LLVM's optimization pipeline on it for the given `-O` level.
+///
+/// At `-O1` and above this runs instcombine, GVN and the loop passes, and from `-O2` on it also inlines.
+/// The module's data layout should be set with `set_target` first, as the passes rely on it.
+pub fn optimize(module: &Module, level: u8) -> Result<(), String> {
+    module.verify().map_err(|error| format!("generated invalid LLVM IR:\n{}", error.to_string()))?;
+
+    if level == 0 {
+        return Ok(());
+    }
+
+    let pass_manager_builder = PassManagerBuilder::create();
+    pass_manager_builder.set_optimization_level(llvm_opt_level(level));
+    if level >= 2 {
+        // The thresholds clang uses for -O2 and -O3
+        pass_manager_builder.set_inliner_with_threshold(if level == 2 { 225 } else { 275 });
+    }
+
+    let pass_manager = PassManager::create(());
+    pass_manager_builder.populate_module_pass_manager(&pass_manager);
+    pass_manager.run_on(module);
+
+    Ok(())
+}
+
+/// Creates a target machine for the given triple, or for the host if there is none.
+fn create_target_machine(triple: Option<&str>, opt_level: OptimizationLevel) -> Result<TargetMachine, String> {
+    let (triple, cpu, features) = match triple {
+        Some(triple) => {
+            Target::initialize_all(&InitializationConfig::default());
+            (TargetTriple::create(triple), "generic".to_string(), String::new())
+        },
+        None => {
+            Target::initialize_native(&InitializationConfig::default())?;
+            (TargetMachine::get_default_triple(), TargetMachine::get_host_cpu_name().to_string(), TargetMachine::get_host_cpu_features().to_string())
+        }
+    };
+
+    let target = Target::from_triple(&triple).map_err(|error| error.to_string())?;
+    target.create_target_machine(&triple, &cpu, &features, opt_level, RelocMode::PIC, CodeModel::Default)
+        .ok_or_else(|| format!("could not create a target machine for {}", triple.as_str().to_string_lossy()))
+}
+
+/// Sets the module's triple and data layout to those of `options.target`, or of the host if there is none
+pub fn set_target(module: &Module, options: &CompileOptions) -> Result<TargetMachine, String> {
+    let machine = create_target_machine(options.target.as_deref(), llvm_opt_level(options.opt_level))?;
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+
+    Ok(machine)
+}
+
+/// Writes the module to `path` as the given kind of output.
+///
+/// Executables are produced by writing a temporary object file and linking it with the system C compiler,
+/// which can be overridden with the `CC` environment variable. Without libc, the object is linked statically
+/// with `ld` instead, or the `LD` environment variable, and WebAssembly modules with `wasm-ld` or `WASM_LD`.
+pub fn write_output(module: &Module, kind: OutputKind, options: &CompileOptions, path: &Path) -> Result<(), Error> {
+    let file_type = match kind {
+        OutputKind::LlvmIr => return module.print_to_file(path).map_err(|error| Error::Llvm(error.to_string())),
+        OutputKind::Bitcode => {
+            return match module.write_bitcode_to_path(path) {
+                true => Ok(()),
+                false => Err(Error::Llvm(format!("could not write bitcode to {}", path.display())))
+            };
+        },
+        OutputKind::Assembly => FileType::Assembly,
+        OutputKind::Object | OutputKind::Executable | OutputKind::Wasm => FileType::Object,
+        OutputKind::C => unreachable!("C output doesn't go through LLVM")
+    };
+
+    let triple = options.target.as_deref();
+    let machine = set_target(module, options).map_err(Error::Llvm)?;
+
+    if let OutputKind::Executable | OutputKind::Wasm = kind {
+        let object_path = temp_path("o");
+        machine.write_to_file(module, file_type, &object_path).map_err(|error| Error::Llvm(error.to_string()))?;
+
+        let result = match triple {
+            Some(triple) if triple.starts_with("wasm32") => link_wasm(&object_path, path),
+            _ if options.no_libc => link_static(&object_path, path),
+            _ => link(&object_path, triple, path)
+        };
+        let _ = std::fs::remove_file(&object_path);
+        result
+    } else {
+        machine.write_to_file(module, file_type, path).map_err(|error| Error::Llvm(error.to_string()))
+    }
+}
+
+fn run_linker(linker: &str, command: &mut Command) -> Result<(), Error> {
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::Link(format!("{} exited with {}", linker, status))),
+        Err(error) => Err(Error::Link(format!("could not run {}: {}", linker, error)))
+    }
+}
+
+fn link(object_path: &Path, triple: Option<&str>, path: &Path) -> Result<(), Error> {
+    let linker = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let mut command = Command::new(&linker);
+    if let Some(triple) = triple {
+        command.arg(format!("--target={}", triple));
+    }
+
+    run_linker(&linker, command.arg("-o").arg(path).arg(object_path))
+}
+
+/// Links a freestanding object, whose entry point is its own `_start`
+fn link_static(object_path: &Path, path: &Path) -> Result<(), Error> {
+    let linker = std::env::var("LD").unwrap_or_else(|_| "ld".to_string());
+
+    run_linker(&linker, Command::new(&linker).arg("-static").arg("-o").arg(path).arg(object_path))
+}
+
+/// Links a WebAssembly object into a module exporting `_start` and its memory, as WASI expects
+fn link_wasm(object_path: &Path, path: &Path) -> Result<(), Error> {
+    let linker = std::env::var("WASM_LD").unwrap_or_else(|_| "wasm-ld".to_string());
+
+    run_linker(&linker, Command::new(&linker).arg("-o").arg(path).arg(object_path))
+}
@@ -0,0 +1,286 @@
+use std::io::{self, Read, Write};
+
+use crate::{EofBehavior, Instruction, Position};
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    Io(io::Error),
+    /// The tape head left the tape, holding the source position of the instruction that moved it there
+    HeadOutOfBounds(Position),
+    /// The program executed more instructions than it was allowed to, which only happens at compile time
+    OutOfSteps
+}
+
+impl From<io::Error> for RuntimeError {
+    fn from(error: io::Error) -> Self {
+        RuntimeError::Io(error)
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::Io(error) => write!(f, "I/O error: {}", error),
+            RuntimeError::HeadOutOfBounds(position) => write!(f, "tape head out of bounds at {}", position),
+            RuntimeError::OutOfSteps => write!(f, "the program ran for too long")
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Executes the instruction tree directly, with the same semantics as the generated code.
+///
+/// Cells of any width are stored as `u64` and wrapped with `cell_mask` after every change.
+struct Interpreter<R: Read, W: Write> {
+    tape: Vec<u64>,
+    head: usize,
+    cell_mask: u64,
+    eof: EofBehavior,
+    input: R,
+    output: W,
+    /// Number of instructions, loop iterations and scan steps the program may still execute
+    steps_left: u64,
+    /// Source position of the last instruction executed that has one
+    position: Position,
+    /// Cells changed so far along with their previous values, when the changes may have to be undone
+    undo_log: Option<Vec<(usize, u64)>>
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    fn cell_index(&self, offset: isize) -> Result<usize, RuntimeError> {
+        let index = self.head as isize + offset;
+        if index < 0 || index as usize >= self.tape.len() {
+            return Err(RuntimeError::HeadOutOfBounds(self.position));
+        }
+
+        Ok(index as usize)
+    }
+
+    fn set_cell(&mut self, index: usize, value: u64) {
+        if let Some(log) = &mut self.undo_log {
+            log.push((index, self.tape[index]));
+        }
+        self.tape[index] = value;
+    }
+
+    /// Restores the cells recorded in the undo log, newest change first
+    fn undo(&mut self) {
+        if let Some(log) = &mut self.undo_log {
+            for (index, value) in log.drain(..).rev() {
+                self.tape[index] = value;
+            }
+        }
+    }
+
+    fn move_head(&mut self, amount: isize) -> Result<(), RuntimeError> {
+        self.head = self.cell_index(amount)?;
+        Ok(())
+    }
+
+    fn add_to_cell(&mut self, offset: isize, amount: i64) -> Result<(), RuntimeError> {
+        let index = self.cell_index(offset)?;
+        self.set_cell(index, self.tape[index].wrapping_add(amount as u64) & self.cell_mask);
+        Ok(())
+    }
+
+    fn read(&mut self, offset: isize) -> Result<(), RuntimeError> {
+        let index = self.cell_index(offset)?;
+        // Make sure prompts are visible before blocking on input
+        self.output.flush()?;
+
+        let mut byte = [0u8];
+        let value = match (self.input.read(&mut byte)?, self.eof) {
+            (0, EofBehavior::Zero) => 0,
+            (0, EofBehavior::MinusOne) => self.cell_mask,
+            (0, EofBehavior::Unchanged) => self.tape[index],
+            _ => byte[0] as u64
+        };
+        self.set_cell(index, value);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: isize) -> Result<(), RuntimeError> {
+        let index = self.cell_index(offset)?;
+        self.output.write_all(&[self.tape[index] as u8])?;
+        Ok(())
+    }
+
+    /// Uses up one of the steps the program may still take
+    fn take_step(&mut self) -> Result<(), RuntimeError> {
+        self.steps_left = self.steps_left.checked_sub(1).ok_or(RuntimeError::OutOfSteps)?;
+        Ok(())
+    }
+
+    fn scan(&mut self, step: isize) -> Result<(), RuntimeError> {
+        while self.tape[self.head] != 0 {
+            self.take_step()?;
+            self.move_head(step)?;
+        }
+
+        Ok(())
+    }
+
+    fn run(&mut self, instructions: &[Instruction]) -> Result<(), RuntimeError> {
+        for instr in instructions {
+            self.take_step()?;
+            if let Some(position) = instr.position() {
+                self.position = position;
+            }
+
+            match instr {
+                Instruction::IncrementPointer(_) => self.move_head(1)?,
+                Instruction::DecrementPointer(_) => self.move_head(-1)?,
+                Instruction::Move { amount, .. } => self.move_head(*amount)?,
+                Instruction::Increment => self.add_to_cell(0, 1)?,
+                Instruction::Decrement => self.add_to_cell(0, -1)?,
+                Instruction::Add(amount) => self.add_to_cell(0, *amount)?,
+                Instruction::AddAt { offset, amount, .. } => self.add_to_cell(*offset, *amount)?,
+                Instruction::Read => self.read(0)?,
+                Instruction::ReadAt { offset, .. } => self.read(*offset)?,
+                Instruction::Write => self.write(0)?,
+                Instruction::WriteAt { offset, .. } => self.write(*offset)?,
+                Instruction::Output(bytes) => self.output.write_all(bytes)?,
+                Instruction::Loop(nested_instructions) => {
+                    while self.tape[self.head] != 0 {
+                        // Count iterations too, as even an empty body must use up the budget
+                        self.take_step()?;
+                        self.run(nested_instructions)?;
+                    }
+                },
+                Instruction::SetZero => self.set_cell(self.head, 0),
+                Instruction::SetZeroAt { offset, .. } => {
+                    let index = self.cell_index(*offset)?;
+                    self.set_cell(index, 0);
+                },
+                Instruction::ScanRight(_) => self.scan(1)?,
+                Instruction::ScanLeft(_) => self.scan(-1)?,
+                Instruction::MulAdd { .. } if self.tape[self.head] == 0 => (),
+                Instruction::MulAdd { offset, factor, .. } => {
+                    let target = self.cell_index(*offset)?;
+                    let product = self.tape[self.head].wrapping_mul(*factor as u64);
+                    self.set_cell(target, self.tape[target].wrapping_add(product) & self.cell_mask);
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs a program on a tape of `tape_size` cells of `cell_bits` bits against the given input and output streams.
+///
+/// Unlike the generated code, the head is always checked to stay on the tape.
+pub fn run<R: Read, W: Write>(instructions: &[Instruction], tape_size: usize, cell_bits: u32, eof: EofBehavior, input: R, output: W) -> Result<(), RuntimeError> {
+    let mut interpreter = Interpreter {
+        tape: vec![0; tape_size],
+        head: 0,
+        cell_mask: u64::MAX >> (64 - cell_bits),
+        eof,
+        input,
+        output,
+        steps_left: u64::MAX,
+        // Nothing before the first instruction with a position can leave the tape
+        position: Position { line: 1, column: 1 },
+        undo_log: None
+    };
+
+    interpreter.run(instructions)?;
+    interpreter.output.flush()?;
+
+    Ok(())
+}
+
+/// State of a program after running its first few top-level instructions
+pub(crate) struct Prefix {
+    /// Number of top-level instructions that ran
+    pub length: usize,
+    pub tape: Vec<u64>,
+    pub head: usize,
+    pub output: Vec<u8>
+}
+
+fn reads_input(instr: &Instruction) -> bool {
+    match instr {
+        Instruction::Read | Instruction::ReadAt { .. } => true,
+        Instruction::Loop(nested_instructions) => nested_instructions.iter().any(reads_input),
+        _ => false
+    }
+}
+
+/// Runs top-level instructions of a program on a fresh tape, stopping before the first that reads input,
+/// moves the head off the tape or doesn't finish within `step_budget` steps in total
+pub(crate) fn run_prefix(instructions: &[Instruction], tape_size: usize, cell_bits: u32, step_budget: u64) -> Prefix {
+    let mut interpreter = Interpreter {
+        tape: vec![0; tape_size],
+        head: 0,
+        cell_mask: u64::MAX >> (64 - cell_bits),
+        eof: EofBehavior::Unchanged,
+        input: io::empty(),
+        output: Vec::new(),
+        steps_left: step_budget,
+        position: Position { line: 1, column: 1 },
+        // Loops can fail halfway through, after changing any number of cells
+        undo_log: Some(Vec::new())
+    };
+
+    let mut length = 0;
+    for instr in instructions.iter().take_while(|instr| !reads_input(instr)) {
+        let (head, output_len) = (interpreter.head, interpreter.output.len());
+
+        if interpreter.run(std::slice::from_ref(instr)).is_err() {
+            interpreter.undo();
+            interpreter.head = head;
+            interpreter.output.truncate(output_len);
+            break;
+        }
+        if let Some(log) = &mut interpreter.undo_log {
+            log.clear();
+        }
+        length += 1;
+    }
+
+    Prefix { length, tape: interpreter.tape, head: interpreter.head, output: interpreter.output }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{interpret, CompileOptions, EofBehavior};
+
+    fn output(source: &str, input: &[u8], options: &CompileOptions) -> Vec<u8> {
+        let mut output = Vec::new();
+        interpret(source, options, input, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn eof_stores_what_the_behavior_asks_for() {
+        // Sets the cell to 7 first, so that leaving it unchanged shows
+        let source = "+++++++,.,.";
+        for bits in [8, 16, 32, 64] {
+            for (eof, expected) in [(EofBehavior::Zero, [b'a', 0]), (EofBehavior::MinusOne, [b'a', 255]), (EofBehavior::Unchanged, [b'a', b'a'])] {
+                let options = CompileOptions { cell_bits: bits, eof, ..CompileOptions::default() };
+                assert_eq!(output(source, b"a", &options), expected, "{:?} with {}-bit cells", eof, bits);
+            }
+        }
+    }
+
+    #[test]
+    fn minus_one_sets_every_bit_of_the_cell() {
+        // Adding one wraps around to zero only if all the bits were set
+        for bits in [8, 16, 32, 64] {
+            let options = CompileOptions { cell_bits: bits, eof: EofBehavior::MinusOne, ..CompileOptions::default() };
+            assert_eq!(output(",+>+<[>-<[-]]>.", b"", &options), [1], "{}-bit cells", bits);
+        }
+    }
+
+    #[test]
+    fn cells_wrap_around_at_their_width() {
+        // Puts 256 in the first cell, then prints whether it is still nonzero
+        let source = "++++++++[>++++++++<-]>[<++++>-]<[>+<[-]]>.";
+        for (bits, expected) in [(8, 0), (16, 1), (32, 1), (64, 1)] {
+            let options = CompileOptions { cell_bits: bits, ..CompileOptions::default() };
+            assert_eq!(output(source, b"", &options), [expected], "{}-bit cells", bits);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use std::os::raw::{c_int, c_long, c_void};
+
+use inkwell::{module::Module, targets::{InitializationConfig, Target}, OptimizationLevel};
+
+extern "C" {
+    fn read(fd: c_int, buf: *mut c_void, count: usize) -> isize;
+    fn write(fd: c_int, buf: *const c_void, count: usize) -> isize;
+    fn memset(s: *mut c_void, c: c_int, n: usize) -> *mut c_void;
+    fn memchr(s: *const c_void, c: c_int, n: usize) -> *mut c_void;
+    fn calloc(nmemb: usize, size: usize) -> *mut c_void;
+    fn mmap(addr: *mut c_void, length: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+    fn sysconf(name: c_int) -> c_long;
+    fn exit(status: c_int) -> !;
+}
+
+type MainFunction = unsafe extern "C" fn() -> c_int;
+
+/// Compiles the module in-process and calls its `main` function.
+///
+/// The libc functions the generated code calls are mapped to the ones this binary is linked against, unless
+/// the module defines them itself as it does without libc.
+pub fn run(module: &Module, opt_level: OptimizationLevel) -> Result<(), String> {
+    Target::initialize_native(&InitializationConfig::default())?;
+
+    let engine = module.create_jit_execution_engine(opt_level).map_err(|error| error.to_string())?;
+
+    let host_functions = [
+        ("read", read as usize),
+        ("write", write as usize),
+        ("memset", memset as usize),
+        ("memchr", memchr as usize),
+        ("calloc", calloc as usize),
+        ("mmap", mmap as usize),
+        ("mprotect", mprotect as usize),
+        ("sysconf", sysconf as usize),
+        ("exit", exit as usize),
+    ];
+    for (name, address) in host_functions {
+        if let Some(function) = module.get_function(name).filter(|function| function.get_first_basic_block().is_none()) {
+            engine.add_global_mapping(&function, address);
+        }
+    }
+
+    unsafe {
+        let main = engine.get_function::<MainFunction>("main").map_err(|error| error.to_string())?;
+        main.call();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,367 @@
+//! A Brainfuck compiler emitting LLVM IR, with an interpreter and a JIT on the side.
+//!
+//! Programs go through [`lex`], [`parse`] and the passes in [`optimize`], then get compiled with [`compile`],
+//! executed in-process with [`jit`], or interpreted with [`interpret`]. [`analysis`] bounds the cells a program
+//! can reach, which the backends use to leave out bounds checks that can't fail. Before code generation,
+//! [`partial_eval`] runs the part of the program that doesn't read input at compile time.
+//!
+//! Everything involving LLVM sits behind the default `llvm` feature. Without it, [`compile`] can still emit C,
+//! or x86-64 assembly with [`CodegenBackend::X86_64`].
+
+use std::{io::{Read, Write}, path::Path};
+
+#[cfg(feature = "llvm")]
+use inkwell::{context::Context, module::Module};
+
+pub mod analysis;
+mod asm_codegen;
+mod backend;
+mod c_codegen;
+#[cfg(feature = "llvm")]
+mod codegen;
+#[cfg(feature = "llvm")]
+mod emit;
+mod interpreter;
+#[cfg(feature = "llvm")]
+mod jit;
+pub mod optimize;
+mod parser;
+pub mod partial_eval;
+#[cfg(feature = "llvm")]
+mod runtime;
+
+pub use interpreter::RuntimeError;
+pub use parser::{instruction_count, lex, parse, pretty_print, Instruction, OpCode, ParseError, Position, Token};
+
+/// What `,` stores in the current cell when the input is exhausted
+#[derive(Clone, Copy, Debug)]
+pub enum EofBehavior {
+    Zero,
+    /// All bits set, which is 255 for 8-bit cells
+    MinusOne,
+    /// Leave the cell as it was
+    Unchanged
+}
+
+/// Where the tape lives
+#[derive(Clone, Copy, Debug)]
+pub enum TapeStorage {
+    /// An array on `main`'s stack
+    Stack,
+    /// A zero-initialized global array
+    Global,
+    /// Memory from `calloc`, or from `mmap` without libc
+    Heap,
+    /// Pages from `mmap`, surrounded by inaccessible guard pages so that leaving the tape faults (Linux only)
+    Mmap
+}
+
+/// Stage of the compiler printed by [`dump`]
+#[derive(Clone, Copy, Debug)]
+pub enum DumpStage {
+    /// The tokens, with their positions
+    Lex,
+    /// The instruction tree straight out of the parser
+    Ast,
+    /// The instruction tree after the optimization passes and compile-time evaluation, as the backends get it
+    OptAst,
+    /// The LLVM module, after LLVM's own optimizations
+    Llvm
+}
+
+/// Code generator used for every output but C
+#[derive(Clone, Copy, Debug)]
+pub enum CodegenBackend {
+    /// LLVM, which optimizes best and supports every target and output kind
+    Llvm,
+    /// A direct translation to x86-64 assembly, fast to compile and independent of LLVM
+    X86_64
+}
+
+/// What [`compile`] writes
+#[derive(Clone, Copy, Debug)]
+pub enum OutputKind {
+    LlvmIr,
+    Bitcode,
+    Assembly,
+    Object,
+    Executable,
+    /// A WebAssembly module for WASI runtimes, linked with `wasm-ld`
+    Wasm,
+    /// C source, which doesn't need LLVM
+    C
+}
+
+impl OutputKind {
+    /// Guesses the kind of output from a file extension, defaulting to an executable
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("ll") => OutputKind::LlvmIr,
+            Some("bc") => OutputKind::Bitcode,
+            Some("s") => OutputKind::Assembly,
+            Some("o") => OutputKind::Object,
+            Some("wasm") => OutputKind::Wasm,
+            Some("c") => OutputKind::C,
+            _ => OutputKind::Executable
+        }
+    }
+
+    /// The conventional file extension for this kind of output
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            OutputKind::LlvmIr => Some("ll"),
+            OutputKind::Bitcode => Some("bc"),
+            OutputKind::Assembly => Some("s"),
+            OutputKind::Object => Some("o"),
+            OutputKind::Executable => None,
+            OutputKind::Wasm => Some("wasm"),
+            OutputKind::C => Some("c")
+        }
+    }
+}
+
+/// Settings affecting how a program is compiled and executed
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// Number of cells on the tape
+    pub tape_size: u64,
+    /// Width of a cell, one of 8, 16, 32 or 64
+    pub cell_bits: u32,
+    pub tape_storage: TapeStorage,
+    pub eof: EofBehavior,
+    /// Whether to abort with an error when the head leaves the tape
+    pub checked: bool,
+    /// LLVM optimization level, from 0 to 3
+    pub opt_level: u8,
+    /// Target triple to generate code for, the host if `None`
+    pub target: Option<String>,
+    /// Loops with at least this many instructions, nested ones included, are generated as functions of their own
+    pub outline_threshold: Option<usize>,
+    /// Whether to make system calls directly and define `_start` instead of linking against libc
+    /// (x86_64 and aarch64 Linux only)
+    pub no_libc: bool,
+    pub backend: CodegenBackend
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            tape_size: 1024,
+            cell_bits: 8,
+            tape_storage: TapeStorage::Stack,
+            eof: EofBehavior::MinusOne,
+            checked: false,
+            opt_level: 0,
+            target: None,
+            outline_threshold: None,
+            no_libc: false,
+            backend: CodegenBackend::Llvm
+        }
+    }
+}
+
+impl CompileOptions {
+    /// Checks the settings every backend relies on, which the fields' types don't enforce
+    pub fn validate(&self) -> Result<(), Error> {
+        if !matches!(self.cell_bits, 8 | 16 | 32 | 64) {
+            return Err(Error::Unsupported(format!("unsupported cell width {}, expected 8, 16, 32 or 64", self.cell_bits)));
+        }
+        // LLVM array types, used for global tapes, are limited to 32-bit lengths
+        if self.tape_size == 0 || self.tape_size > u32::MAX as u64 {
+            return Err(Error::Unsupported(format!("invalid tape size {}, expected a number of cells between 1 and {}", self.tape_size, u32::MAX)));
+        }
+        if self.opt_level > 3 {
+            return Err(Error::Unsupported(format!("unknown optimization level {}, expected 0 to 3", self.opt_level)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Parse(ParseError),
+    Runtime(RuntimeError),
+    /// LLVM rejected the module, or could not emit or run it
+    Llvm(String),
+    /// The options ask for something the target can't do
+    Unsupported(String),
+    /// Writing the output failed
+    Io(std::io::Error),
+    /// The assembler or linker could not be run, or failed
+    Link(String)
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Error::Parse(error)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(error: RuntimeError) -> Self {
+        Error::Runtime(error)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse(error) => write!(f, "{}", error),
+            Error::Runtime(error) => write!(f, "{}", error),
+            Error::Llvm(error) => write!(f, "{}", error),
+            Error::Unsupported(error) => write!(f, "{}", error),
+            Error::Io(error) => write!(f, "could not write the output: {}", error),
+            Error::Link(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Lexes, parses and optimizes a program
+pub fn parse_source(source: &str) -> Result<Vec<Instruction>, ParseError> {
+    Ok(optimize::optimize(parse(lex(source))?))
+}
+
+/// Generates the LLVM module for a program, verified and optimized for `options.target` according to `options.opt_level`
+#[cfg(feature = "llvm")]
+pub fn compile_module<'ctx>(context: &'ctx Context, program: &[Instruction], options: &CompileOptions) -> Result<Module<'ctx>, Error> {
+    options.validate()?;
+    let module = codegen::generate_llvm(context, program, options).map_err(Error::Unsupported)?;
+    emit::set_target(&module, options).map_err(Error::Llvm)?;
+    emit::optimize(&module, options.opt_level).map_err(Error::Llvm)?;
+
+    Ok(module)
+}
+
+/// Target triple used for [`OutputKind::Wasm`] when no other WebAssembly target is given
+pub const WASI_TARGET: &str = "wasm32-wasi";
+
+/// A fresh path in the temporary directory for an intermediate file, so that none of the user's files get clobbered
+pub(crate) fn temp_path(extension: &str) -> std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("rustfuck-{}-{}.{}", std::process::id(), id, extension))
+}
+
+/// Compiles a program and writes it to `path` as the given kind of output
+pub fn compile(source: &str, options: &CompileOptions, kind: OutputKind, path: &Path) -> Result<(), Error> {
+    options.validate()?;
+    let program = partial_eval::evaluate_prefix(parse_source(source)?, options);
+
+    match (kind, options.backend) {
+        (OutputKind::C, _) => {
+            let code = c_codegen::generate_c(&program, options).map_err(Error::Unsupported)?;
+            std::fs::write(path, code).map_err(Error::Io)
+        },
+        (_, CodegenBackend::X86_64) => {
+            let asm = asm_codegen::generate_asm(&program, options).map_err(Error::Unsupported)?;
+            asm_codegen::write_asm(&asm, kind, path)
+        },
+        (_, CodegenBackend::Llvm) => compile_llvm(&program, options, kind, path)
+    }
+}
+
+#[cfg(feature = "llvm")]
+fn compile_llvm(program: &[Instruction], options: &CompileOptions, kind: OutputKind, path: &Path) -> Result<(), Error> {
+    let mut options = options.clone();
+    if let OutputKind::Wasm = kind {
+        match options.target.as_deref() {
+            None => options.target = Some(WASI_TARGET.to_string()),
+            Some(triple) if triple.starts_with("wasm32") => (),
+            Some(triple) => return Err(Error::Unsupported(format!("cannot emit WebAssembly for {}", triple)))
+        }
+    }
+
+    let context = Context::create();
+    let module = compile_module(&context, program, &options)?;
+    emit::write_output(&module, kind, &options, path)
+}
+
+#[cfg(not(feature = "llvm"))]
+fn compile_llvm(_program: &[Instruction], _options: &CompileOptions, _kind: OutputKind, _path: &Path) -> Result<(), Error> {
+    Err(Error::Unsupported("rustfuck was built without the `llvm` feature, only C output and the x86-64 backend are available".to_string()))
+}
+
+/// Compiles a program in memory and runs it right away, with the process' stdin and stdout
+#[cfg(feature = "llvm")]
+pub fn jit(source: &str, options: &CompileOptions) -> Result<(), Error> {
+    options.validate()?;
+    let program = partial_eval::evaluate_prefix(parse_source(source)?, options);
+
+    let context = Context::create();
+    let module = compile_module(&context, &program, options)?;
+    jit::run(&module, emit::llvm_opt_level(options.opt_level)).map_err(Error::Llvm)
+}
+
+/// Compiles a program in memory and runs it right away, which needs the `llvm` feature
+#[cfg(not(feature = "llvm"))]
+pub fn jit(_source: &str, _options: &CompileOptions) -> Result<(), Error> {
+    Err(Error::Unsupported("rustfuck was built without the `llvm` feature, the JIT is not available".to_string()))
+}
+
+/// Renders an intermediate stage of compiling a program as text
+pub fn dump(source: &str, options: &CompileOptions, stage: DumpStage) -> Result<String, Error> {
+    options.validate()?;
+    match stage {
+        DumpStage::Lex => Ok(lex(source).iter().map(|token| format!("{}:{} {:?}\n", token.position.line, token.position.column, token.op)).collect()),
+        DumpStage::Ast => Ok(pretty_print(&parse(lex(source))?)),
+        DumpStage::OptAst => Ok(pretty_print(&partial_eval::evaluate_prefix(parse_source(source)?, options))),
+        DumpStage::Llvm => dump_llvm(source, options)
+    }
+}
+
+#[cfg(feature = "llvm")]
+fn dump_llvm(source: &str, options: &CompileOptions) -> Result<String, Error> {
+    let program = partial_eval::evaluate_prefix(parse_source(source)?, options);
+
+    let context = Context::create();
+    let module = compile_module(&context, &program, options)?;
+    Ok(module.print_to_string().to_string())
+}
+
+#[cfg(not(feature = "llvm"))]
+fn dump_llvm(_source: &str, _options: &CompileOptions) -> Result<String, Error> {
+    Err(Error::Unsupported("rustfuck was built without the `llvm` feature, there is no LLVM module to dump".to_string()))
+}
+
+/// Number of instructions, counting loops and everything in them, before and after a pass
+#[derive(Clone, Debug)]
+pub struct PassStats {
+    pub pass: &'static str,
+    pub before: usize,
+    pub after: usize
+}
+
+/// Runs the passes in [`optimize::PASSES`] and then compile-time evaluation one at a time, counting instructions
+pub fn pass_stats(source: &str, options: &CompileOptions) -> Result<Vec<PassStats>, Error> {
+    options.validate()?;
+    let mut program = parse(lex(source))?;
+    let mut stats = Vec::new();
+
+    for (pass, run) in optimize::PASSES {
+        let before = instruction_count(&program);
+        program = run(program);
+        stats.push(PassStats { pass, before, after: instruction_count(&program) });
+    }
+
+    let before = instruction_count(&program);
+    program = partial_eval::evaluate_prefix(program, options);
+    stats.push(PassStats { pass: "evaluate-prefix", before, after: instruction_count(&program) });
+
+    Ok(stats)
+}
+
+/// Runs a program with the interpreter against the given input and output streams.
+///
+/// The interpreter runs the instruction tree as parsed, without any optimization pass, so that it can serve as
+/// a reference for what the passes and backends should do.
+pub fn interpret<R: Read, W: Write>(source: &str, options: &CompileOptions, input: R, output: W) -> Result<(), Error> {
+    options.validate()?;
+    let program = parse(lex(source))?;
+
+    interpreter::run(&program, options.tape_size as usize, options.cell_bits, options.eof, input, output)?;
+    Ok(())
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+
+use crate::{Instruction, Position};
+
+/// A pass rewriting the instruction tree
+pub type Pass = fn(Vec<Instruction>) -> Vec<Instruction>;
+
+/// Every pass with its name, in the order `optimize` runs them
+pub const PASSES: [(&str, Pass); 4] = [
+    ("fold-runs", fold_runs),
+    ("recognize-idioms", recognize_idioms),
+    ("eliminate-dead-code", eliminate_dead_code),
+    ("address-offsets", address_offsets)
+];
+
+/// Runs every pass, in order
+pub fn optimize(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    PASSES.iter().fold(instructions, |instructions, (_, pass)| pass(instructions))
+}
+
+/// Folds runs of `+`/`-` into `Add(n)` and runs of `>`/`<` into `Move(n)`.
+///
+/// Runs that cancel out completely are dropped, and loop bodies are folded recursively.
+pub fn fold_runs(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut folded: Vec<Instruction> = Vec::new();
+
+    for instr in instructions {
+        let instr = match instr {
+            Instruction::Increment => Instruction::Add(1),
+            Instruction::Decrement => Instruction::Add(-1),
+            Instruction::IncrementPointer(position) => Instruction::Move { amount: 1, position: Some(position) },
+            Instruction::DecrementPointer(position) => Instruction::Move { amount: -1, position: Some(position) },
+            Instruction::Loop(nested_instructions) => Instruction::Loop(fold_runs(nested_instructions)),
+            other => other
+        };
+
+        match (folded.last_mut(), instr) {
+            (Some(Instruction::Add(total)), Instruction::Add(amount)) => {
+                *total = total.wrapping_add(amount);
+                if *total == 0 {
+                    folded.pop();
+                }
+            },
+            (Some(Instruction::Move { amount: total, position: last }), Instruction::Move { amount, position }) => {
+                *total += amount;
+                // The run leaves the head where its last step put it
+                *last = position.or(*last);
+                if *total == 0 {
+                    folded.pop();
+                }
+            },
+            (_, instr) => folded.push(instr)
+        }
+    }
+
+    folded
+}
+
+/// Replaces common loop idioms with dedicated instructions.
+///
+/// * `[-]` and `[+]` become `SetZero`
+/// * `[>]` and `[<]` become `ScanRight` and `ScanLeft`
+/// * balanced transfer loops such as `[->+>++<<]` become a `MulAdd` per target cell, followed by `SetZero`
+///
+/// This expects loop bodies to already be folded by `fold_runs`.
+pub fn recognize_idioms(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut recognized = Vec::new();
+
+    for instr in instructions {
+        match instr {
+            Instruction::Loop(nested_instructions) => {
+                let nested_instructions = recognize_idioms(nested_instructions);
+
+                match nested_instructions.as_slice() {
+                    [Instruction::Move { amount: 1, position }] => recognized.push(Instruction::ScanRight(*position)),
+                    [Instruction::Move { amount: -1, position }] => recognized.push(Instruction::ScanLeft(*position)),
+                    body => match transfer_targets(body) {
+                        Some(targets) => {
+                            let mul_adds = targets.into_iter().map(|(offset, factor, position)| Instruction::MulAdd { offset, factor, position });
+                            recognized.extend(mul_adds);
+                            recognized.push(Instruction::SetZero);
+                        },
+                        None => recognized.push(Instruction::Loop(nested_instructions))
+                    }
+                }
+            },
+            other => recognized.push(other)
+        }
+    }
+
+    recognized
+}
+
+/// Returns the `(offset, factor, position)` triples of a loop body that only adds multiples of the current cell
+/// to its neighbours, or `None` if the body is not such a transfer loop. The position is that of the move
+/// reaching the target.
+///
+/// A body is a transfer loop if it only consists of `Add` and `Move`, ends where it started
+/// and changes the current cell by exactly one per iteration.
+fn transfer_targets(body: &[Instruction]) -> Option<Vec<(isize, i64, Option<Position>)>> {
+    let mut deltas: BTreeMap<isize, (i64, Option<Position>)> = BTreeMap::new();
+    let mut offset = 0;
+    let mut last_move = None;
+
+    for instr in body {
+        match instr {
+            Instruction::Add(amount) => {
+                let (delta, _) = deltas.entry(offset).or_insert((0, last_move));
+                *delta = delta.wrapping_add(*amount);
+            },
+            Instruction::Move { amount, position } => {
+                offset += amount;
+                last_move = *position;
+            },
+            _ => return None
+        }
+    }
+
+    if offset != 0 {
+        return None;
+    }
+
+    let (step, _) = deltas.remove(&0).unwrap_or((0, None));
+    let targets: Vec<_> = deltas.into_iter()
+        .filter(|&(_, (factor, _))| factor != 0)
+        .map(|(offset, (factor, position))| (offset, factor, position))
+        .collect();
+
+    match step {
+        -1 => Some(targets),
+        // `[+]` also reaches zero by wrapping around, but then the iteration count is not the cell value
+        1 if targets.is_empty() => Some(targets),
+        _ => None
+    }
+}
+
+/// Removes code that can't have any effect.
+///
+/// * loops, scans, `SetZero` and `MulAdd` on a cell known to be zero: any cell before the program changes one,
+///   such as in a comment loop at the top of the file, and the current cell right after a loop or scan
+/// * `Add` and `Move` runs that cancel out once the code between them is gone
+/// * `Add` and `SetZero` at the end of the program, after the last I/O, loop, scan or `MulAdd`
+///
+/// Loops and scans at the end are kept, as they might not terminate, and so are moves and `MulAdd`s, so that
+/// `--checked` still reports them leaving the tape.
+///
+/// This expects the output of `recognize_idioms`.
+pub fn eliminate_dead_code(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut live = fold_runs(remove_no_ops(instructions, true));
+
+    let end = live.iter().rposition(|instr| !matches!(instr, Instruction::Add(_) | Instruction::Move { .. } | Instruction::SetZero));
+    let tail = live.split_off(end.map_or(0, |last| last + 1));
+    live.extend(fold_runs(tail.into_iter().filter(|instr| matches!(instr, Instruction::Move { .. })).collect()));
+
+    live
+}
+
+/// Drops the instructions that do nothing because the current cell is zero.
+///
+/// `pristine` tells that no cell has been changed yet, so that every cell is zero.
+fn remove_no_ops(instructions: Vec<Instruction>, mut pristine: bool) -> Vec<Instruction> {
+    let mut live = Vec::new();
+    // Whether the current cell is known to be zero
+    let mut zero = pristine;
+
+    for instr in instructions {
+        match instr {
+            Instruction::Loop(_) | Instruction::ScanRight(_) | Instruction::ScanLeft(_) | Instruction::SetZero | Instruction::MulAdd { .. } if zero => continue,
+            Instruction::Loop(nested_instructions) => {
+                // An iteration only starts on a nonzero cell
+                live.push(Instruction::Loop(remove_no_ops(nested_instructions, false)));
+                zero = true;
+                continue;
+            },
+            Instruction::Move { .. } => zero = pristine,
+            Instruction::SetZero | Instruction::ScanRight(_) | Instruction::ScanLeft(_) => zero = true,
+            Instruction::MulAdd { .. } => pristine = false,
+            Instruction::Write => (),
+            _ => {
+                pristine = false;
+                zero = false;
+            }
+        }
+
+        live.push(instr);
+    }
+
+    live
+}
+
+/// Gives cell operations an offset from the head, so that straight-line code moves the head only once.
+///
+/// In a segment of `Add`, `SetZero`, `Read`, `Write` and `Move`, the moves are summed up and the cell
+/// operations become `AddAt`, `SetZeroAt`, `ReadAt` and `WriteAt`, followed by a single `Move` for the net
+/// movement. `>+>+<<` becomes `AddAt { offset: 1, amount: 1 }, AddAt { offset: 2, amount: 1 }`, with no move at all.
+///
+/// Loops, scans and `MulAdd` end a segment, and loop bodies are rewritten recursively.
+pub fn address_offsets(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut addressed = Vec::new();
+    let mut offset = 0;
+    // Where the segment last moved, which is what brought the virtual head to `offset`
+    let mut position = None;
+
+    for instr in instructions {
+        match instr {
+            Instruction::IncrementPointer(at) => {
+                offset += 1;
+                position = Some(at);
+            },
+            Instruction::DecrementPointer(at) => {
+                offset -= 1;
+                position = Some(at);
+            },
+            Instruction::Move { amount, position: at } => {
+                offset += amount;
+                position = at.or(position);
+            },
+            Instruction::Increment => addressed.push(Instruction::AddAt { offset, amount: 1, position }),
+            Instruction::Decrement => addressed.push(Instruction::AddAt { offset, amount: -1, position }),
+            Instruction::Add(amount) => addressed.push(Instruction::AddAt { offset, amount, position }),
+            Instruction::SetZero => addressed.push(Instruction::SetZeroAt { offset, position }),
+            Instruction::Read => addressed.push(Instruction::ReadAt { offset, position }),
+            Instruction::Write => addressed.push(Instruction::WriteAt { offset, position }),
+            other => {
+                // The rest works relative to the actual head, so catch up with the segment's movement
+                if offset != 0 {
+                    addressed.push(Instruction::Move { amount: offset, position });
+                    offset = 0;
+                }
+                position = None;
+
+                addressed.push(match other {
+                    Instruction::Loop(nested_instructions) => Instruction::Loop(address_offsets(nested_instructions)),
+                    other => other
+                });
+            }
+        }
+    }
+
+    if offset != 0 {
+        addressed.push(Instruction::Move { amount: offset, position });
+    }
+
+    addressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex, parse};
+
+    fn program(source: &str) -> Vec<Instruction> {
+        parse(lex(source)).unwrap()
+    }
+
+    /// Position of a character on the first line
+    fn at(column: usize) -> Option<Position> {
+        Some(Position { line: 1, column })
+    }
+
+    fn move_to(amount: isize, column: usize) -> Instruction {
+        Instruction::Move { amount, position: at(column) }
+    }
+
+    #[test]
+    fn fold_runs_merges_runs() {
+        assert_eq!(fold_runs(program("+++>>-<")), vec![Instruction::Add(3), move_to(2, 5), Instruction::Add(-1), move_to(-1, 7)]);
+    }
+
+    #[test]
+    fn fold_runs_drops_runs_that_cancel_out() {
+        assert_eq!(fold_runs(program("+-><.")), vec![Instruction::Write]);
+        assert_eq!(fold_runs(program("++>><<--")), vec![]);
+    }
+
+    #[test]
+    fn fold_runs_folds_loop_bodies() {
+        assert_eq!(fold_runs(program("[--<<]")), vec![Instruction::Loop(vec![Instruction::Add(-2), move_to(-2, 5)])]);
+    }
+
+    #[test]
+    fn recognize_idioms_finds_clear_and_scan_loops() {
+        let idioms = recognize_idioms(fold_runs(program("[-][+][>][<]")));
+        assert_eq!(idioms, vec![Instruction::SetZero, Instruction::SetZero, Instruction::ScanRight(at(8)), Instruction::ScanLeft(at(11))]);
+    }
+
+    #[test]
+    fn recognize_idioms_turns_transfer_loops_into_mul_adds() {
+        assert_eq!(recognize_idioms(fold_runs(program("[->+>+++<<]"))), vec![
+            Instruction::MulAdd { offset: 1, factor: 1, position: at(3) },
+            Instruction::MulAdd { offset: 2, factor: 3, position: at(5) },
+            Instruction::SetZero
+        ]);
+        assert_eq!(recognize_idioms(fold_runs(program("[<<-->>-]"))), vec![
+            Instruction::MulAdd { offset: -2, factor: -2, position: at(3) },
+            Instruction::SetZero
+        ]);
+    }
+
+    #[test]
+    fn recognize_idioms_keeps_other_loops() {
+        // Unbalanced, stepping by two, and doing I/O
+        for source in ["[->+]", "[-->+<]", "[->.<]"] {
+            let folded = fold_runs(program(source));
+            assert_eq!(recognize_idioms(folded.clone()), folded);
+        }
+    }
+
+    #[test]
+    fn address_offsets_removes_intermediate_moves() {
+        assert_eq!(address_offsets(program(">+>+<<")), vec![
+            Instruction::AddAt { offset: 1, amount: 1, position: at(1) },
+            Instruction::AddAt { offset: 2, amount: 1, position: at(3) }
+        ]);
+        assert_eq!(address_offsets(program(">,<<.")), vec![
+            Instruction::ReadAt { offset: 1, position: at(1) },
+            Instruction::WriteAt { offset: -1, position: at(4) },
+            move_to(-1, 4)
+        ]);
+    }
+
+    #[test]
+    fn address_offsets_catches_up_before_loops() {
+        let addressed = address_offsets(fold_runs(program(">>+[>-]")));
+        assert_eq!(addressed, vec![
+            Instruction::AddAt { offset: 2, amount: 1, position: at(2) },
+            move_to(2, 2),
+            Instruction::Loop(vec![Instruction::AddAt { offset: 1, amount: -1, position: at(5) }, move_to(1, 5)])
+        ]);
+    }
+
+    fn without_dead_code(source: &str) -> Vec<Instruction> {
+        eliminate_dead_code(recognize_idioms(fold_runs(program(source))))
+    }
+
+    #[test]
+    fn eliminate_dead_code_drops_comment_loops_at_the_start() {
+        assert_eq!(without_dead_code("[comment, with. brackets[]]>[-]+."), vec![move_to(1, 28), Instruction::Add(1), Instruction::Write]);
+    }
+
+    #[test]
+    fn eliminate_dead_code_drops_loops_right_after_loops() {
+        assert_eq!(without_dead_code(",[.,][.][-]."), vec![
+            Instruction::Read,
+            Instruction::Loop(vec![Instruction::Write, Instruction::Read]),
+            Instruction::Write
+        ]);
+    }
+
+    #[test]
+    fn eliminate_dead_code_merges_runs_around_dropped_code() {
+        assert_eq!(without_dead_code(">[-]<+."), vec![Instruction::Add(1), Instruction::Write]);
+        assert_eq!(without_dead_code(",[.,]+[.][-]-."), vec![
+            Instruction::Read,
+            Instruction::Loop(vec![Instruction::Write, Instruction::Read]),
+            Instruction::Add(1),
+            Instruction::Loop(vec![Instruction::Write]),
+            Instruction::Add(-1),
+            Instruction::Write
+        ]);
+    }
+
+    #[test]
+    fn eliminate_dead_code_drops_updates_after_the_last_output() {
+        assert_eq!(without_dead_code("+.>++<-<"), vec![Instruction::Add(1), Instruction::Write, move_to(-1, 8)]);
+        assert_eq!(without_dead_code("+.>++[->+<]>-"), vec![
+            Instruction::Add(1),
+            Instruction::Write,
+            move_to(1, 3),
+            Instruction::Add(2),
+            Instruction::MulAdd { offset: 1, factor: 1, position: at(8) },
+            move_to(1, 12)
+        ]);
+        // A loop might not terminate, so it stays
+        assert_eq!(without_dead_code("+.[+>]>+"), vec![
+            Instruction::Add(1),
+            Instruction::Write,
+            Instruction::Loop(vec![Instruction::Add(1), move_to(1, 5)]),
+            move_to(1, 7)
+        ]);
+    }
+}
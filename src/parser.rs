@@ -0,0 +1,273 @@
+#[derive(Clone, Debug)]
+pub enum OpCode {
+    IncrementPointer,
+    DecrementPointer,
+    Increment,
+    Decrement,
+    Read,
+    Write,
+    LoopBegin,
+    LoopEnd
+}
+
+/// A node of the program tree.
+///
+/// Instructions that move the head or touch a cell other than the current one carry the source position of the
+/// `>` or `<` that brought the head there, which bounds checks report. It is `None` for instructions the compiler
+/// made up, which stay on the tape.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    IncrementPointer(Position),
+    DecrementPointer(Position),
+    Increment,
+    Decrement,
+    Read,
+    Write,
+    Loop(Vec<Instruction>),
+    /// Adds a (wrapping) amount to the current cell, produced by `optimize::fold_runs`
+    Add(i64),
+    /// Moves the tape head by a signed amount, produced by `optimize::fold_runs`
+    Move { amount: isize, position: Option<Position> },
+    /// Sets the current cell to zero, produced by `optimize::recognize_idioms`
+    SetZero,
+    /// Moves the tape head right until it reaches a zero cell, produced by `optimize::recognize_idioms`
+    ScanRight(Option<Position>),
+    /// Moves the tape head left until it reaches a zero cell, produced by `optimize::recognize_idioms`
+    ScanLeft(Option<Position>),
+    /// Adds the current cell multiplied by `factor` to the cell at `offset`, produced by `optimize::recognize_idioms`.
+    ///
+    /// Like the loop it replaces, it doesn't touch the target at all when the current cell is zero, and the target
+    /// may then be off the tape.
+    MulAdd { offset: isize, factor: i64, position: Option<Position> },
+    /// Adds a (wrapping) amount to the cell at `offset` from the head, produced by `optimize::address_offsets`
+    AddAt { offset: isize, amount: i64, position: Option<Position> },
+    /// Sets the cell at `offset` from the head to zero, produced by `optimize::address_offsets`
+    SetZeroAt { offset: isize, position: Option<Position> },
+    /// Reads into the cell at `offset` from the head, produced by `optimize::address_offsets`
+    ReadAt { offset: isize, position: Option<Position> },
+    /// Writes the cell at `offset` from the head, produced by `optimize::address_offsets`
+    WriteAt { offset: isize, position: Option<Position> },
+    /// Writes a constant string, produced by `partial_eval::evaluate_prefix`
+    Output(Vec<u8>)
+}
+
+impl Instruction {
+    /// Source position a failing bounds check on this instruction reports, if it has one
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Instruction::IncrementPointer(position) | Instruction::DecrementPointer(position) => Some(*position),
+            Instruction::Move { position, .. }
+            | Instruction::ScanRight(position)
+            | Instruction::ScanLeft(position)
+            | Instruction::MulAdd { position, .. }
+            | Instruction::AddAt { position, .. }
+            | Instruction::SetZeroAt { position, .. }
+            | Instruction::ReadAt { position, .. }
+            | Instruction::WriteAt { position, .. } => *position,
+            _ => None
+        }
+    }
+}
+
+/// Counts the instructions in a program, including those nested in loops
+pub fn instruction_count(instructions: &[Instruction]) -> usize {
+    instructions.iter().map(|instr| match instr {
+        Instruction::Loop(nested_instructions) => 1 + instruction_count(nested_instructions),
+        _ => 1
+    }).sum()
+}
+
+/// Formats a program with one instruction per line, indenting loop bodies under their `Loop`
+pub fn pretty_print(instructions: &[Instruction]) -> String {
+    let mut printed = String::new();
+    print_tree(instructions, 0, &mut printed);
+    printed
+}
+
+fn print_tree(instructions: &[Instruction], depth: usize, printed: &mut String) {
+    for instr in instructions {
+        printed.push_str(&"  ".repeat(depth));
+        match instr {
+            Instruction::Loop(nested_instructions) => {
+                printed.push_str("Loop\n");
+                print_tree(nested_instructions, depth + 1, printed);
+            },
+            Instruction::Output(bytes) => printed.push_str(&format!("Output({:?})\n", String::from_utf8_lossy(bytes))),
+            other => printed.push_str(&format!("{:?}\n", other))
+        }
+    }
+}
+
+/// A 1-based line and column in the source
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// Short enough to keep `--dump opt-ast` readable
+impl std::fmt::Debug for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub op: OpCode,
+    pub position: Position
+}
+
+#[derive(Clone, Debug)]
+pub enum ParseError {
+    /// A `]` without a matching `[`
+    UnmatchedLoopEnd(Position),
+    /// A `[` that is never closed
+    UnclosedLoop(Position)
+}
+
+impl ParseError {
+    pub fn position(&self) -> Position {
+        match self {
+            ParseError::UnmatchedLoopEnd(position) | ParseError::UnclosedLoop(position) => *position
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            ParseError::UnmatchedLoopEnd(_) => "this loop has no beginning",
+            ParseError::UnclosedLoop(_) => "this loop is never closed"
+        }
+    }
+
+    /// Formats the error as a rustc-style diagnostic pointing at the offending bracket
+    pub fn render(&self, source: &str, path: &str) -> String {
+        let position = self.position();
+        let line = source.lines().nth(position.line - 1).unwrap_or("");
+        let gutter = " ".repeat(position.line.to_string().len());
+        // Keep tabs so the caret lines up with the source line
+        let indent: String = line.chars().take(position.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+
+        format!(
+            "error: {}\n{gutter}--> {}:{}:{}\n{gutter} |\n{} | {}\n{gutter} | {}^ {}\n",
+            self, path, position.line, position.column, position.line, line, indent, self.label(),
+            gutter = gutter
+        )
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnmatchedLoopEnd(_) => write!(f, "unmatched `]`"),
+            ParseError::UnclosedLoop(_) => write!(f, "unclosed `[`")
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn lex(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        for (column_index, symbol) in line.chars().enumerate() {
+            let op = match symbol {
+                '>' => Some(OpCode::IncrementPointer),
+                '<' => Some(OpCode::DecrementPointer),
+                '+' => Some(OpCode::Increment),
+                '-' => Some(OpCode::Decrement),
+                ',' => Some(OpCode::Read),
+                '.' => Some(OpCode::Write),
+                '[' => Some(OpCode::LoopBegin),
+                ']' => Some(OpCode::LoopEnd),
+                _ => None
+            };
+
+            if let Some(op) = op {
+                tokens.push(Token { op, position: Position { line: line_index + 1, column: column_index + 1 } })
+            }
+        }
+    }
+
+    tokens
+}
+
+pub fn parse(tokens: Vec<Token>) -> Result<Vec<Instruction>, ParseError> {
+    let mut program: Vec<Instruction> = Vec::new();
+    // For every open loop, the position of its `[` and the instructions surrounding it
+    let mut open_loops: Vec<(Position, Vec<Instruction>)> = Vec::new();
+
+    for token in tokens {
+        let instr = match token.op {
+            OpCode::IncrementPointer => Instruction::IncrementPointer(token.position),
+            OpCode::DecrementPointer => Instruction::DecrementPointer(token.position),
+            OpCode::Increment => Instruction::Increment,
+            OpCode::Decrement => Instruction::Decrement,
+            OpCode::Read => Instruction::Read,
+            OpCode::Write => Instruction::Write,
+            OpCode::LoopBegin => {
+                open_loops.push((token.position, std::mem::take(&mut program)));
+                continue;
+            },
+            OpCode::LoopEnd => {
+                let (_, surrounding) = open_loops.pop().ok_or(ParseError::UnmatchedLoopEnd(token.position))?;
+                Instruction::Loop(std::mem::replace(&mut program, surrounding))
+            }
+        };
+
+        program.push(instr);
+    }
+
+    if let Some((position, _)) = open_loops.pop() {
+        return Err(ParseError::UnclosedLoop(position));
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_position(source: &str) -> (usize, usize) {
+        let position = parse(lex(source)).unwrap_err().position();
+        (position.line, position.column)
+    }
+
+    #[test]
+    fn parse_nests_loops() {
+        let program = parse(lex("+[>[-]<]")).unwrap();
+        let inner = Instruction::Loop(vec![Instruction::Decrement]);
+        let outer = Instruction::Loop(vec![
+            Instruction::IncrementPointer(Position { line: 1, column: 3 }),
+            inner,
+            Instruction::DecrementPointer(Position { line: 1, column: 7 })
+        ]);
+        assert_eq!(program, vec![Instruction::Increment, outer]);
+    }
+
+    #[test]
+    fn unmatched_loop_end_points_at_the_bracket() {
+        assert!(matches!(parse(lex("+]")), Err(ParseError::UnmatchedLoopEnd(_))));
+        assert_eq!(error_position("[-]\n  +]"), (2, 4));
+    }
+
+    #[test]
+    fn unclosed_loop_points_at_the_innermost_open_bracket() {
+        assert!(matches!(parse(lex("[")), Err(ParseError::UnclosedLoop(_))));
+        assert_eq!(error_position("comment\n [ [+] [-"), (2, 8));
+    }
+
+    #[test]
+    fn render_underlines_the_bracket() {
+        let rendered = parse(lex("+\n\t+]")).unwrap_err().render("+\n\t+]", "test.b");
+        assert_eq!(rendered, "error: unmatched `]`\n --> test.b:2:3\n  |\n2 | \t+]\n  | \t ^ this loop has no beginning\n");
+    }
+}
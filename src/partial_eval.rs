@@ -0,0 +1,110 @@
+//! Compile-time execution of the part of a program that doesn't depend on its input.
+
+use crate::{interpreter, CompileOptions, Instruction};
+
+/// Number of instructions, loop iterations and scan steps executed at compile time before giving up on the rest
+/// of the program
+pub const STEP_BUDGET: u64 = 1_000_000;
+
+/// Largest tape that gets simulated at compile time
+const MAX_TAPE_SIZE: u64 = 1 << 20;
+
+/// Runs the start of a program at compile time, up to its first `,` or until it used up [`STEP_BUDGET`],
+/// and replaces it with an `Output` of what it printed and `AddAt`s and a `Move` recreating the tape it left behind.
+///
+/// Only whole top-level instructions are evaluated: a loop that reads input, runs too long or moves the head off
+/// the tape is kept for the generated code to run, along with everything after it. A program that runs to
+/// completion becomes a single `Output`. Programs are returned untouched when the options don't pass
+/// [`CompileOptions::validate`].
+pub fn evaluate_prefix(program: Vec<Instruction>, options: &CompileOptions) -> Vec<Instruction> {
+    if options.validate().is_err() || options.tape_size > MAX_TAPE_SIZE {
+        return program;
+    }
+
+    let prefix = interpreter::run_prefix(&program, options.tape_size as usize, options.cell_bits, STEP_BUDGET);
+    if prefix.length == 0 {
+        return program;
+    }
+
+    let mut evaluated = Vec::new();
+    if !prefix.output.is_empty() {
+        evaluated.push(Instruction::Output(prefix.output));
+    }
+
+    // Nothing looks at the tape once the whole program ran
+    if prefix.length < program.len() {
+        let cells = prefix.tape.iter().enumerate().filter(|&(_, &value)| value != 0);
+        evaluated.extend(cells.map(|(cell, &value)| Instruction::AddAt { offset: cell as isize, amount: value as i64, position: None }));
+        if prefix.head != 0 {
+            evaluated.push(Instruction::Move { amount: prefix.head as isize, position: None });
+        }
+    }
+
+    evaluated.extend(program.into_iter().skip(prefix.length));
+    evaluated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_source, Position};
+
+    /// Position of a character on the first line
+    fn at(column: usize) -> Option<Position> {
+        Some(Position { line: 1, column })
+    }
+
+    fn evaluate(source: &str) -> Vec<Instruction> {
+        evaluate_prefix(parse_source(source).unwrap(), &CompileOptions::default())
+    }
+
+    #[test]
+    fn programs_without_input_become_a_single_output() {
+        assert_eq!(evaluate("++++++++[>++++++++<-]>+.+."), vec![Instruction::Output(b"AB".to_vec())]);
+    }
+
+    #[test]
+    fn evaluation_stops_at_the_first_read() {
+        assert_eq!(evaluate("++.>+++,."), vec![
+            Instruction::Output(vec![2]),
+            Instruction::AddAt { offset: 0, amount: 2, position: None },
+            Instruction::AddAt { offset: 1, amount: 3, position: None },
+            Instruction::ReadAt { offset: 1, position: at(4) },
+            Instruction::WriteAt { offset: 1, position: at(4) },
+            Instruction::Move { amount: 1, position: at(4) }
+        ]);
+    }
+
+    #[test]
+    fn endless_loops_run_out_of_steps() {
+        for source in ["+[]", "+[<>]", "+[[]]", "+[>+<[<+>-]]."] {
+            let program = parse_source(source).unwrap();
+            assert_eq!(evaluate_prefix(program.clone(), &CompileOptions::default()), program);
+        }
+    }
+
+    #[test]
+    fn leaving_the_tape_is_left_to_the_generated_code() {
+        assert_eq!(evaluate("+.<+."), vec![
+            Instruction::Output(vec![1]),
+            Instruction::AddAt { offset: 0, amount: 1, position: None },
+            Instruction::AddAt { offset: -1, amount: 1, position: at(3) },
+            Instruction::WriteAt { offset: -1, position: at(3) },
+            Instruction::Move { amount: -1, position: at(3) }
+        ]);
+    }
+
+    #[test]
+    fn failed_loops_restore_large_tapes() {
+        // Each top-level loop used to copy the whole tape, in case it had to be rolled back
+        let source = "+[-]".repeat(10_000) + "+[>+]";
+        let options = CompileOptions { tape_size: 1 << 20, ..CompileOptions::default() };
+        assert_eq!(evaluate_prefix(parse_source(&source).unwrap(), &options), vec![
+            Instruction::AddAt { offset: 0, amount: 1, position: None },
+            Instruction::Loop(vec![
+                Instruction::AddAt { offset: 1, amount: 1, position: at(source.len() - 2) },
+                Instruction::Move { amount: 1, position: at(source.len() - 2) }
+            ])
+        ]);
+    }
+}
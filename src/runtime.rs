@@ -0,0 +1,503 @@
+use inkwell::{attributes::{Attribute, AttributeLoc}, builder::Builder, context::Context, module::{Linkage, Module}, targets::TargetMachine, types::FunctionType, values::{CallableValue, FunctionValue}, AddressSpace, IntPredicate};
+
+use crate::{CompileOptions, TapeStorage};
+
+/// Size of the output buffer, in bytes
+const BUFFER_SIZE: u64 = 4096;
+
+/// Page size assumed without libc to ask the system for it, a multiple of every page size Linux uses and the
+/// size of a WebAssembly page
+const NO_LIBC_PAGE_SIZE: u64 = 65536;
+
+/// Linux `mmap` and `mprotect` flags
+pub(crate) const PROT_NONE: u64 = 0;
+pub(crate) const PROT_READ_WRITE: u64 = 3;
+pub(crate) const MAP_PRIVATE_ANONYMOUS: u64 = 0x22;
+
+/// Module WASI functions are imported from
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// Where the generated code gets its system services from
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Platform {
+    Libc,
+    /// Raw Linux system calls, with `--no-libc`
+    Linux(SyscallArch),
+    /// WASI imports, for wasm32 targets
+    Wasi
+}
+
+impl Platform {
+    /// Picks the platform for the target triple, or the host if there is none, rejecting mmap tapes off Linux
+    pub(crate) fn for_options(options: &CompileOptions) -> Result<Self, String> {
+        let triple = match &options.target {
+            Some(triple) => triple.clone(),
+            None => TargetMachine::get_default_triple().as_str().to_string_lossy().into_owned()
+        };
+
+        if triple.starts_with("wasm32") {
+            return match options.tape_storage {
+                TapeStorage::Mmap => Err("WebAssembly has no virtual memory for an mmap tape".to_string()),
+                _ => Ok(Platform::Wasi)
+            };
+        }
+        if let (TapeStorage::Mmap, false) = (options.tape_storage, triple.contains("linux")) {
+            // The mmap flags and the sysconf name for the page size are Linux's
+            return Err(format!("mmap tapes are only supported on Linux, not {}", triple));
+        }
+
+        match options.no_libc {
+            true => Ok(Platform::Linux(SyscallArch::from_triple(&triple)?)),
+            false => Ok(Platform::Libc)
+        }
+    }
+
+    /// Whether the program is its own entry point, rather than libc calling its `main`
+    pub(crate) fn defines_start(self) -> bool {
+        !matches!(self, Platform::Libc)
+    }
+}
+
+/// Operating system services the generated code relies on, taken from libc, raw system calls or WASI
+pub(crate) struct System<'ctx> {
+    /// `i64 read(i32 fd, i8* buf, i64 count)`
+    pub read: FunctionValue<'ctx>,
+    /// `i64 write(i32 fd, i8* buf, i64 count)`
+    pub write: FunctionValue<'ctx>,
+    /// `void exit(i32 status)`, which never returns
+    pub exit: FunctionValue<'ctx>,
+    /// `i8* mmap(i8* addr, i64 length, i32 prot, i32 flags, i32 fd, i64 offset)`, absent on WASI
+    pub mmap: Option<FunctionValue<'ctx>>,
+    /// `i32 mprotect(i8* addr, i64 length, i32 prot)`, absent on WASI
+    pub mprotect: Option<FunctionValue<'ctx>>,
+    /// `i64 rustfuck_page_size()`
+    pub page_size: FunctionValue<'ctx>,
+    /// `i8* rustfuck_alloc_zeroed(i64 size)` allocates zeroed memory that is never freed
+    pub alloc_zeroed: FunctionValue<'ctx>,
+    /// `i8* memchr(i8* s, i32 c, i64 n)`, only taken from libc
+    pub memchr: Option<FunctionValue<'ctx>>
+}
+
+/// Linux system call numbers and calling convention of an architecture
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SyscallArch {
+    X86_64,
+    Aarch64
+}
+
+impl SyscallArch {
+    fn from_triple(triple: &str) -> Result<Self, String> {
+        let arch = match triple.split('-').next() {
+            Some("x86_64") => SyscallArch::X86_64,
+            Some("aarch64") => SyscallArch::Aarch64,
+            _ => return Err(format!("building without libc is only supported on x86_64 and aarch64, not {}", triple))
+        };
+
+        if !triple.contains("linux") {
+            return Err(format!("building without libc is only supported on Linux, not {}", triple));
+        }
+
+        Ok(arch)
+    }
+
+    /// The numbers of `read`, `write`, `mmap`, `mprotect` and `exit_group`
+    fn numbers(self) -> [u64; 5] {
+        match self {
+            SyscallArch::X86_64 => [0, 1, 9, 10, 231],
+            SyscallArch::Aarch64 => [63, 64, 222, 226, 94]
+        }
+    }
+
+    /// The instruction and constraints of a call taking the number and six arguments
+    fn inline_asm(self) -> (&'static str, &'static str) {
+        match self {
+            SyscallArch::X86_64 => ("syscall", "={rax},{rax},{rdi},{rsi},{rdx},{r10},{r8},{r9},~{rcx},~{r11},~{memory}"),
+            SyscallArch::Aarch64 => ("svc #0", "={x0},{x8},{x0},{x1},{x2},{x3},{x4},{x5},~{memory}")
+        }
+    }
+}
+
+fn system_types<'ctx>(context: &'ctx Context) -> [(&'static str, FunctionType<'ctx>); 5] {
+    let void = context.void_type();
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let byte_ptr_type = context.i8_type().ptr_type(AddressSpace::Generic);
+
+    let io_type = i64_type.fn_type(&[i32_type.into(), byte_ptr_type.into(), i64_type.into()], false);
+    [
+        ("read", io_type),
+        ("write", io_type),
+        ("mmap", byte_ptr_type.fn_type(&[byte_ptr_type.into(), i64_type.into(), i32_type.into(), i32_type.into(), i32_type.into(), i64_type.into()], false)),
+        ("mprotect", i32_type.fn_type(&[byte_ptr_type.into(), i64_type.into(), i32_type.into()], false)),
+        ("exit", void.fn_type(&[i32_type.into()], false))
+    ]
+}
+
+/// Declares the system services from libc, or defines them on top of system calls or WASI imports.
+///
+/// Without libc, this also defines the `memset` LLVM may emit calls to.
+pub(crate) fn build_system<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>, platform: Platform) -> System<'ctx> {
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let byte_ptr_type = context.i8_type().ptr_type(AddressSpace::Generic);
+
+    let (read, write, exit, mmap, mprotect) = match platform {
+        Platform::Libc => {
+            let [read, write, mmap, mprotect, exit] = system_types(context).map(|(name, fn_type)| module.add_function(name, fn_type, None));
+            (read, write, exit, Some(mmap), Some(mprotect))
+        },
+        Platform::Linux(arch) => {
+            build_memset(context, module, builder);
+            let [read, write, mmap, mprotect, exit] = build_syscalls(context, module, builder, arch);
+            (read, write, exit, Some(mmap), Some(mprotect))
+        },
+        Platform::Wasi => {
+            build_memset(context, module, builder);
+            let (read, write, exit) = build_wasi(context, module, builder);
+            (read, write, exit, None, None)
+        }
+    };
+
+    // rustfuck_page_size
+    let page_size = module.add_function("rustfuck_page_size", i64_type.fn_type(&[], false), Some(Linkage::Internal));
+    builder.position_at_end(context.append_basic_block(page_size, "entry"));
+    let size = match platform {
+        Platform::Libc => {
+            // Linux value of _SC_PAGESIZE
+            let sysconf = module.add_function("sysconf", i64_type.fn_type(&[i32_type.into()], false), None);
+            builder.build_call(sysconf, &[i32_type.const_int(30, false).into()], "")
+                .try_as_basic_value().expect_left("sysconf call returned no value :(").into_int_value()
+        },
+        Platform::Linux(_) | Platform::Wasi => i64_type.const_int(NO_LIBC_PAGE_SIZE, false)
+    };
+    builder.build_return(Some(&size));
+
+    // rustfuck_alloc_zeroed
+    let alloc_zeroed = module.add_function("rustfuck_alloc_zeroed", byte_ptr_type.fn_type(&[i64_type.into()], false), Some(Linkage::Internal));
+    builder.position_at_end(context.append_basic_block(alloc_zeroed, "entry"));
+    let size = alloc_zeroed.get_nth_param(0).unwrap().into_int_value();
+    let memory = match platform {
+        Platform::Libc => {
+            let calloc = module.add_function("calloc", byte_ptr_type.fn_type(&[i64_type.into(), i64_type.into()], false), None);
+            builder.build_call(calloc, &[size.into(), i64_type.const_int(1, false).into()], "")
+                .try_as_basic_value().expect_left("calloc call returned no value :(").into_pointer_value()
+        },
+        Platform::Linux(_) => {
+            // Anonymous mappings are zeroed
+            let args = [
+                byte_ptr_type.const_null().into(),
+                size.into(),
+                i32_type.const_int(PROT_READ_WRITE, false).into(),
+                i32_type.const_int(MAP_PRIVATE_ANONYMOUS, false).into(),
+                i32_type.const_all_ones().into(),
+                i64_type.const_zero().into()
+            ];
+            builder.build_call(mmap.unwrap(), &args, "")
+                .try_as_basic_value().expect_left("mmap call returned no value :(").into_pointer_value()
+        },
+        Platform::Wasi => {
+            // Grow the linear memory by enough pages, which are zeroed, and hand out the first new one
+            let memory_grow = module.add_function("llvm.wasm.memory.grow.i32", i32_type.fn_type(&[i32_type.into(), i32_type.into()], false), None);
+            let page_mask = i64_type.const_int(NO_LIBC_PAGE_SIZE - 1, false);
+            let pages = builder.build_int_unsigned_div(builder.build_int_add(size, page_mask, ""), i64_type.const_int(NO_LIBC_PAGE_SIZE, false), "");
+            let args = [i32_type.const_zero().into(), builder.build_int_truncate(pages, i32_type, "").into()];
+            let first_page = builder.build_call(memory_grow, &args, "")
+                .try_as_basic_value().expect_left("memory.grow returned no value :(").into_int_value();
+            let address = builder.build_int_mul(first_page, i32_type.const_int(NO_LIBC_PAGE_SIZE, false), "");
+            builder.build_int_to_ptr(address, byte_ptr_type, "")
+        }
+    };
+    builder.build_return(Some(&memory));
+
+    let memchr = match platform {
+        Platform::Libc => Some(module.add_function("memchr", byte_ptr_type.fn_type(&[byte_ptr_type.into(), i32_type.into(), i64_type.into()], false), None)),
+        Platform::Linux(_) | Platform::Wasi => None
+    };
+
+    System { read, write, exit, mmap, mprotect, page_size, alloc_zeroed, memchr }
+}
+
+/// Defines `read`, `write` and `exit` on top of WASI's `fd_read`, `fd_write` and `proc_exit` imports
+fn build_wasi<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>) -> (FunctionValue<'ctx>, FunctionValue<'ctx>, FunctionValue<'ctx>) {
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let byte_ptr_type = context.i8_type().ptr_type(AddressSpace::Generic);
+    // A `{ buf, len }` pair, with wasm32's 32-bit sizes
+    let iovec_type = context.struct_type(&[byte_ptr_type.into(), i32_type.into()], false);
+
+    let import = |name: &str, fn_type: FunctionType<'ctx>| {
+        let function = module.add_function(name, fn_type, None);
+        function.add_attribute(AttributeLoc::Function, context.create_string_attribute("wasm-import-module", WASI_MODULE));
+        function.add_attribute(AttributeLoc::Function, context.create_string_attribute("wasm-import-name", name));
+        function
+    };
+    // `errno fd_read(fd, const iovec* iovs, size iovs_len, size* nread)`, and the same for fd_write
+    let fd_io_type = i32_type.fn_type(&[i32_type.into(), iovec_type.ptr_type(AddressSpace::Generic).into(), i32_type.into(), i32_type.ptr_type(AddressSpace::Generic).into()], false);
+    let fd_read = import("fd_read", fd_io_type);
+    let fd_write = import("fd_write", fd_io_type);
+    let proc_exit = import("proc_exit", context.void_type().fn_type(&[i32_type.into()], false));
+
+    let [read, write, _, _, exit] = system_types(context);
+    let [read, write, exit] = [read, write, exit].map(|(name, fn_type)| module.add_function(&format!("rustfuck_sys_{}", name), fn_type, Some(Linkage::Internal)));
+
+    for (function, import) in [(read, fd_read), (write, fd_write)] {
+        builder.position_at_end(context.append_basic_block(function, "entry"));
+        let fd = function.get_nth_param(0).unwrap();
+        let buf = function.get_nth_param(1).unwrap().into_pointer_value();
+        let count = builder.build_int_truncate(function.get_nth_param(2).unwrap().into_int_value(), i32_type, "");
+
+        let iovec = builder.build_alloca(iovec_type, "iovec");
+        builder.build_store(builder.build_struct_gep(iovec, 0, "").unwrap(), buf);
+        builder.build_store(builder.build_struct_gep(iovec, 1, "").unwrap(), count);
+        let transferred = builder.build_alloca(i32_type, "transferred");
+
+        let args = [fd.into(), iovec.into(), i32_type.const_int(1, false).into(), transferred.into()];
+        let errno = builder.build_call(import, &args, "").try_as_basic_value().expect_left("WASI call returned no value :(").into_int_value();
+        // Report errors as -1, like the system calls do with a negated errno
+        let failed = builder.build_int_compare(IntPredicate::NE, errno, i32_type.const_zero(), "");
+        let transferred = builder.build_int_z_extend(builder.build_load(transferred, "").into_int_value(), i64_type, "");
+        let result = builder.build_select(failed, i64_type.const_all_ones(), transferred, "");
+        builder.build_return(Some(&result));
+    }
+
+    builder.position_at_end(context.append_basic_block(exit, "entry"));
+    builder.build_call(proc_exit, &[exit.get_nth_param(0).unwrap().into()], "");
+    builder.build_unreachable();
+
+    (read, write, exit)
+}
+
+/// Defines `read`, `write`, `mmap`, `mprotect` and `exit` as internal functions making system calls
+fn build_syscalls<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>, arch: SyscallArch) -> [FunctionValue<'ctx>; 5] {
+    let i64_type = context.i64_type();
+
+    let (instruction, constraints) = arch.inline_asm();
+    let syscall_type = i64_type.fn_type(&[i64_type.into(); 7], false);
+    let syscall = context.create_inline_asm(syscall_type, instruction.to_string(), constraints.to_string(), true, false, None, false);
+
+    let mut functions = system_types(context).map(|(name, fn_type)| module.add_function(&format!("rustfuck_sys_{}", name), fn_type, Some(Linkage::Internal)));
+    for (function, number) in functions.iter_mut().zip(arch.numbers()) {
+        builder.position_at_end(context.append_basic_block(*function, "entry"));
+
+        // Pass every argument as a 64-bit register, padding with zeros
+        let mut args = vec![i64_type.const_int(number, false).into()];
+        for param in function.get_param_iter() {
+            let arg = match param.is_pointer_value() {
+                true => builder.build_ptr_to_int(param.into_pointer_value(), i64_type, ""),
+                false => builder.build_int_s_extend_or_bit_cast(param.into_int_value(), i64_type, "")
+            };
+            args.push(arg.into());
+        }
+        args.resize(7, i64_type.const_zero().into());
+
+        let result = builder.build_call(CallableValue::try_from(syscall).unwrap(), &args, "")
+            .try_as_basic_value().expect_left("system call returned no value :(").into_int_value();
+
+        match function.get_type().get_return_type() {
+            None => {
+                // Only exit returns nothing, and it does not return at all
+                builder.build_unreachable();
+            },
+            Some(return_type) if return_type.is_pointer_type() => {
+                let pointer = builder.build_int_to_ptr(result, return_type.into_pointer_type(), "");
+                builder.build_return(Some(&pointer));
+            },
+            Some(return_type) => {
+                let result = builder.build_int_truncate_or_bit_cast(result, return_type.into_int_type(), "");
+                builder.build_return(Some(&result));
+            }
+        }
+    }
+
+    functions
+}
+
+/// Defines `memset`, which LLVM expects to exist even without libc.
+///
+/// It is marked `no-builtins` so that LLVM doesn't turn its loop back into a call to itself.
+fn build_memset<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>) {
+    let i8_type = context.i8_type();
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let byte_ptr_type = i8_type.ptr_type(AddressSpace::Generic);
+
+    let memset_type = byte_ptr_type.fn_type(&[byte_ptr_type.into(), i32_type.into(), i64_type.into()], false);
+    let memset = module.add_function("memset", memset_type, None);
+    memset.add_attribute(AttributeLoc::Function, context.create_string_attribute("no-builtins", ""));
+
+    let entry = context.append_basic_block(memset, "entry");
+    let loop_cond = context.append_basic_block(memset, "loopcond");
+    let loop_body = context.append_basic_block(memset, "loop");
+    let done = context.append_basic_block(memset, "done");
+
+    builder.position_at_end(entry);
+    let dest = memset.get_nth_param(0).unwrap().into_pointer_value();
+    let value = builder.build_int_truncate(memset.get_nth_param(1).unwrap().into_int_value(), i8_type, "");
+    let len = memset.get_nth_param(2).unwrap().into_int_value();
+    builder.build_unconditional_branch(loop_cond);
+
+    builder.position_at_end(loop_cond);
+    let index = builder.build_phi(i64_type, "i");
+    index.add_incoming(&[(&i64_type.const_zero(), entry)]);
+    let index_val = index.as_basic_value().into_int_value();
+    let finished = builder.build_int_compare(IntPredicate::UGE, index_val, len, "");
+    builder.build_conditional_branch(finished, done, loop_body);
+
+    builder.position_at_end(loop_body);
+    builder.build_store(unsafe { builder.build_gep(dest, &[index_val], "") }, value);
+    index.add_incoming(&[(&builder.build_int_add(index_val, i64_type.const_int(1, false), ""), loop_body)]);
+    builder.build_unconditional_branch(loop_cond);
+
+    builder.position_at_end(done);
+    builder.build_return(Some(&dest));
+}
+
+/// Defines the `_start` entry point used without libc, which runs `main` and exits
+pub(crate) fn build_start<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>, main: FunctionValue<'ctx>, system: &System<'ctx>, platform: Platform) {
+    let start = module.add_function("_start", context.void_type().fn_type(&[], false), None);
+    if let Platform::Linux(_) = platform {
+        // The stack is 16-byte aligned at the entry point, not 8 bytes off like after a call
+        let align_stack = context.create_enum_attribute(Attribute::get_named_enum_kind_id("alignstack"), 16);
+        start.add_attribute(AttributeLoc::Function, align_stack);
+    }
+
+    builder.position_at_end(context.append_basic_block(start, "entry"));
+    builder.build_call(main, &[], "");
+    builder.build_call(system.exit, &[context.i32_type().const_zero().into()], "");
+    builder.build_unreachable();
+}
+
+/// Functions of the I/O runtime emitted into every module
+pub(crate) struct Runtime<'ctx> {
+    /// `void rustfuck_putchar(int c, i64 count)` appends `count` copies of `c` to the output buffer
+    pub putchar: FunctionValue<'ctx>,
+    /// `int rustfuck_getchar()` flushes the output, then reads a byte from stdin, returning -1 at EOF
+    pub getchar: FunctionValue<'ctx>,
+    /// `void rustfuck_flush()` writes out the output buffer
+    pub flush: FunctionValue<'ctx>,
+    /// `void rustfuck_write_all(i8* data, i64 len)` writes `len` bytes to stdout, bypassing the output buffer
+    pub write_all: FunctionValue<'ctx>
+}
+
+/// Defines the buffered I/O runtime on top of the `read` and `write` system services.
+///
+/// The output buffer is flushed after a newline, when it is full, before reading and when the program exits.
+pub(crate) fn build_runtime<'ctx>(context: &'ctx Context, module: &Module<'ctx>, builder: &Builder<'ctx>, system: &System<'ctx>) -> Runtime<'ctx> {
+    let void = context.void_type();
+    let i8_type = context.i8_type();
+    let i32_type = context.i32_type();
+    let i64_type = context.i64_type();
+    let byte_ptr_type = i8_type.ptr_type(AddressSpace::Generic);
+
+    let (read, write) = (system.read, system.write);
+
+    let buffer_type = i8_type.array_type(BUFFER_SIZE as u32);
+    let buffer = module.add_global(buffer_type, None, "output_buffer");
+    buffer.set_linkage(Linkage::Internal);
+    buffer.set_initializer(&buffer_type.const_zero());
+    let buffer = buffer.as_pointer_value().const_cast(byte_ptr_type);
+
+    let buffer_len = module.add_global(i64_type, None, "output_buffer_len");
+    buffer_len.set_linkage(Linkage::Internal);
+    buffer_len.set_initializer(&i64_type.const_zero());
+    let buffer_len = buffer_len.as_pointer_value();
+
+    // rustfuck_write_all: write a whole string to stdout, retrying on partial writes
+    let write_all_type = void.fn_type(&[byte_ptr_type.into(), i64_type.into()], false);
+    let write_all = module.add_function("rustfuck_write_all", write_all_type, Some(Linkage::Internal));
+    let entry = context.append_basic_block(write_all, "entry");
+    let write_block = context.append_basic_block(write_all, "write");
+    let done = context.append_basic_block(write_all, "done");
+
+    builder.position_at_end(entry);
+    let data = write_all.get_nth_param(0).unwrap().into_pointer_value();
+    let len = write_all.get_nth_param(1).unwrap().into_int_value();
+    builder.build_unconditional_branch(write_block);
+
+    builder.position_at_end(write_block);
+    let written = builder.build_phi(i64_type, "written");
+    written.add_incoming(&[(&i64_type.const_zero(), entry)]);
+    let written_val = written.as_basic_value().into_int_value();
+    let remaining = builder.build_int_sub(len, written_val, "");
+    let is_empty = builder.build_int_compare(IntPredicate::SLE, remaining, i64_type.const_zero(), "");
+    let write_call = context.append_basic_block(write_all, "writecall");
+    builder.build_conditional_branch(is_empty, done, write_call);
+
+    builder.position_at_end(write_call);
+    let start = unsafe { builder.build_gep(data, &[written_val], "") };
+    let args = [i32_type.const_int(1, false).into(), start.into(), remaining.into()];
+    let result = builder.build_call(write, &args, "").try_as_basic_value().expect_left("write call returned no value :(").into_int_value();
+    // Give up on errors rather than spinning forever
+    let failed = builder.build_int_compare(IntPredicate::SLE, result, i64_type.const_zero(), "");
+    written.add_incoming(&[(&builder.build_int_add(written_val, result, ""), write_call)]);
+    builder.build_conditional_branch(failed, done, write_block);
+
+    builder.position_at_end(done);
+    builder.build_return(None);
+
+    // rustfuck_flush: write out the buffer and empty it
+    let flush = module.add_function("rustfuck_flush", void.fn_type(&[], false), Some(Linkage::Internal));
+    builder.position_at_end(context.append_basic_block(flush, "entry"));
+    let len = builder.build_load(buffer_len, "len").into_int_value();
+    builder.build_call(write_all, &[buffer.into(), len.into()], "");
+    builder.build_store(buffer_len, i64_type.const_zero());
+    builder.build_return(None);
+
+    // rustfuck_putchar: append `count` copies of a character, flushing on newlines and when the buffer is full
+    let putchar = module.add_function("rustfuck_putchar", void.fn_type(&[i32_type.into(), i64_type.into()], false), Some(Linkage::Internal));
+    let entry = context.append_basic_block(putchar, "entry");
+    let loop_cond = context.append_basic_block(putchar, "loopcond");
+    let loop_body = context.append_basic_block(putchar, "loop");
+    let flush_block = context.append_basic_block(putchar, "flush");
+    let next = context.append_basic_block(putchar, "next");
+    let done = context.append_basic_block(putchar, "done");
+
+    builder.position_at_end(entry);
+    let char = builder.build_int_truncate(putchar.get_nth_param(0).unwrap().into_int_value(), i8_type, "");
+    let count = putchar.get_nth_param(1).unwrap().into_int_value();
+    let is_newline = builder.build_int_compare(IntPredicate::EQ, char, i8_type.const_int(b'\n' as u64, false), "");
+    builder.build_unconditional_branch(loop_cond);
+
+    builder.position_at_end(loop_cond);
+    let index = builder.build_phi(i64_type, "i");
+    index.add_incoming(&[(&i64_type.const_zero(), entry)]);
+    let index_val = index.as_basic_value().into_int_value();
+    let finished = builder.build_int_compare(IntPredicate::UGE, index_val, count, "");
+    builder.build_conditional_branch(finished, done, loop_body);
+
+    builder.position_at_end(loop_body);
+    let len = builder.build_load(buffer_len, "len").into_int_value();
+    let slot = unsafe { builder.build_gep(buffer, &[len], "") };
+    builder.build_store(slot, char);
+    let new_len = builder.build_int_add(len, i64_type.const_int(1, false), "");
+    builder.build_store(buffer_len, new_len);
+    let is_full = builder.build_int_compare(IntPredicate::EQ, new_len, i64_type.const_int(BUFFER_SIZE, false), "");
+    let should_flush = builder.build_or(is_full, is_newline, "");
+    builder.build_conditional_branch(should_flush, flush_block, next);
+
+    builder.position_at_end(flush_block);
+    builder.build_call(flush, &[], "");
+    builder.build_unconditional_branch(next);
+
+    builder.position_at_end(next);
+    index.add_incoming(&[(&builder.build_int_add(index_val, i64_type.const_int(1, false), ""), next)]);
+    builder.build_unconditional_branch(loop_cond);
+
+    builder.position_at_end(done);
+    builder.build_return(None);
+
+    // rustfuck_getchar: flush so that prompts are visible, then read a single byte
+    let getchar = module.add_function("rustfuck_getchar", i32_type.fn_type(&[], false), Some(Linkage::Internal));
+    builder.position_at_end(context.append_basic_block(getchar, "entry"));
+    builder.build_call(flush, &[], "");
+
+    let byte = builder.build_alloca(i8_type, "byte");
+    let args = [i32_type.const_zero().into(), byte.into(), i64_type.const_int(1, false).into()];
+    let result = builder.build_call(read, &args, "").try_as_basic_value().expect_left("read call returned no value :(").into_int_value();
+    let got_byte = builder.build_int_compare(IntPredicate::EQ, result, i64_type.const_int(1, false), "");
+    let char = builder.build_int_z_extend(builder.build_load(byte, "").into_int_value(), i32_type, "");
+    let char = builder.build_select(got_byte, char, i32_type.const_all_ones(), "");
+    builder.build_return(Some(&char));
+
+    Runtime { putchar, getchar, flush, write_all }
+}